@@ -0,0 +1,174 @@
+// Copyright 2025 Luis M. B. Varona
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The error type returned by the core functionality of `amsg-batch`.
+
+use crate::DisplayNumber;
+use std::{io, time::Duration};
+use thiserror::Error;
+
+/// A `Result` whose error defaults to [`AmsgError`].
+pub type Result<T, E = AmsgError> = std::result::Result<T, E>;
+
+/// The ways in which loading recipients or sending messages can fail.
+#[derive(Debug, Error)]
+pub enum AmsgError {
+    /// An invalid command-line value (e.g., a malformed duration or placeholder)
+    #[error("{0}")]
+    InvalidArgument(String),
+    /// A file that does not exist
+    #[error("Path {0} does not exist")]
+    FileNotFound(String),
+    /// A path that exists but is not a file with the expected extension
+    #[error("{0}")]
+    InvalidPath(String),
+    /// A phone number that failed validation or normalization
+    #[error("Phone number {} {reason}", DisplayNumber(number))]
+    InvalidNumber { number: String, reason: String },
+    /// Recipients whose layout does not match the options (e.g., a missing column)
+    #[error("{0}")]
+    InvalidRecipients(String),
+    /// A single recipient that could not be read (e.g., with a malformed delay)
+    #[error("Invalid recipient on line {line}: {reason}")]
+    InvalidRecord { line: u64, reason: String },
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{context}")]
+    Csv {
+        context: String,
+        #[source]
+        source: csv::Error,
+    },
+    #[error("{context}")]
+    Json {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("{context}")]
+    Xlsx {
+        context: String,
+        #[source]
+        source: calamine::XlsxError,
+    },
+    #[cfg(feature = "webhook")]
+    #[error("{context}")]
+    Http {
+        context: String,
+        #[source]
+        source: Box<ureq::Error>,
+    },
+    #[cfg(feature = "history")]
+    #[error("{context}")]
+    Sqlite {
+        context: String,
+        #[source]
+        source: rusqlite::Error,
+    },
+    /// A non-zero exit from `osascript`, carrying its stderr
+    #[error("AppleScript execution failed: {}", describe_apple_script_error(.0))]
+    AppleScriptFailed(String),
+    /// An `osascript` run that was killed for exceeding the send timeout
+    #[error("AppleScript timed out after {}", humantime::format_duration(*.0))]
+    Timeout(Duration),
+}
+
+impl AmsgError {
+    /// Whether the failure was due to the recipient not being registered with the service.
+    pub fn is_not_registered(&self) -> bool {
+        // Messages reports unknown buddies as missing objects (errAENoSuchObject)
+        self.apple_script_error_code() == Some(-1728)
+    }
+
+    /// The AppleScript error number reported by a failed `osascript` run, if any.
+    pub fn apple_script_error_code(&self) -> Option<i32> {
+        match self {
+            AmsgError::AppleScriptFailed(stderr) => parse_apple_script_error_code(stderr),
+            _ => None,
+        }
+    }
+
+    /// Whether the error was an I/O failure (e.g., an unreadable file), after which reading
+    /// recipients cannot carry on.
+    pub fn is_io(&self) -> bool {
+        match self {
+            AmsgError::Io { .. } => true,
+            AmsgError::Csv { source, .. } => source.is_io_error(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| AmsgError::Io { context, source }
+    }
+
+    pub(crate) fn csv(context: impl Into<String>) -> impl FnOnce(csv::Error) -> Self {
+        let context = context.into();
+        move |source| AmsgError::Csv { context, source }
+    }
+
+    pub(crate) fn json(context: impl Into<String>) -> impl FnOnce(serde_json::Error) -> Self {
+        let context = context.into();
+        move |source| AmsgError::Json { context, source }
+    }
+
+    #[cfg(feature = "webhook")]
+    pub(crate) fn http(context: impl Into<String>) -> impl FnOnce(ureq::Error) -> Self {
+        let context = context.into();
+        move |source| AmsgError::Http {
+            context,
+            source: Box::new(source),
+        }
+    }
+
+    #[cfg(feature = "history")]
+    pub(crate) fn sqlite(context: impl Into<String>) -> impl FnOnce(rusqlite::Error) -> Self {
+        let context = context.into();
+        move |source| AmsgError::Sqlite { context, source }
+    }
+
+    pub(crate) fn xlsx(context: impl Into<String>) -> impl FnOnce(calamine::XlsxError) -> Self {
+        let context = context.into();
+        move |source| AmsgError::Xlsx { context, source }
+    }
+}
+
+// `osascript` ends its error messages with the error number in parentheses (e.g., `(-1728)`)
+fn parse_apple_script_error_code(stderr: &str) -> Option<i32> {
+    let (_, code) = stderr.trim_end().strip_suffix(')')?.rsplit_once('(')?;
+    code.parse().ok()
+}
+
+// Explains the errors Messages commonly reports, falling back to the raw stderr otherwise
+fn describe_apple_script_error(stderr: &str) -> String {
+    let Some(code) = parse_apple_script_error_code(stderr) else {
+        return stderr.trim().to_string();
+    };
+
+    let reason = match code {
+        -128 => "the script was cancelled",
+        -600 => "Messages is not running",
+        -609 => "the connection to Messages was lost",
+        -1700 => "a value could not be converted (e.g., a malformed attachment path)",
+        -1708 => "Messages did not understand the command",
+        -1712 => "Messages took too long to respond",
+        -1719 => "the service is not available (is it enabled in Messages?)",
+        -1728 => "buddy not found (the recipient is not registered with the service)",
+        -1743 => {
+            "not permitted to control Messages (allow it under System Settings > Privacy & \
+             Security > Automation)"
+        }
+        _ => return stderr.trim().to_string(),
+    };
+
+    format!("{} ({})", reason, code)
+}
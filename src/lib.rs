@@ -0,0 +1,5729 @@
+// Copyright 2025 Luis M. B. Varona
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Core functionality of `amsg-batch`: loading and normalizing recipients, reading message
+//! templates, and sending texts via Apple Messages with AppleScript.
+
+mod error;
+
+pub use error::{AmsgError, Result};
+
+use calamine::{Data, Reader, Xlsx, open_workbook};
+use chrono::{DateTime, Local};
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter, Writer, WriterBuilder};
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use flate2::read::MultiGzDecoder;
+use phonenumber::{Mode, Type, country, metadata::DATABASE};
+use rand::{Rng, RngExt, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env, fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        Mutex, OnceLock, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+/// The recipients path denoting stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// The file extensions accepted for recipient files (`.csv.gz` files being decompressed as read).
+pub const RECIPIENT_EXTENSIONS: &[&str] = &["csv", "csv.gz", "xlsx", "json", "vcf"];
+
+/// The default minimum number of digits in a phone number.
+pub const MIN_NUMBER_LENGTH: usize = 7;
+/// The default maximum number of digits in a phone number.
+pub const MAX_NUMBER_LENGTH: usize = 15;
+
+// The GSM 03.38 basic character set, plus the extension characters (which take two septets)
+const GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+                          ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+const GSM7_EXTENSION: &str = "\x0C^{}\\[~]|€";
+
+// The byte order mark Excel on Windows writes at the start of UTF-8 CSV
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// The least an adaptive delay grows by after a failure, however short the usual delay
+const ADAPTIVE_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The built-in placeholder replaced with the first word of each recipient's name.
+pub const FIRST_NAME_PLACEHOLDER: &str = "{firstname}";
+
+/// The built-in placeholder replaced with each recipient's 1-based position in the send order.
+pub const INDEX_PLACEHOLDER: &str = "{index}";
+
+/// A message recipient, with an optional name for personalization.
+#[derive(Clone)]
+pub struct Recipient {
+    pub name: Option<String>,
+    pub number: String,
+    pub attachment: Option<String>,
+    /// The (1-based) line of the CSV on which the recipient's record starts (or, for JSON,
+    /// the 1-based index of the recipient in the array)
+    pub line: u64,
+    /// The value to substitute for each placeholder token
+    pub fields: HashMap<String, String>,
+    /// The recipient's own message, used in place of the shared template if present
+    pub message: Option<String>,
+    /// The service to send to the recipient with, used in place of the default if present
+    pub service: Option<String>,
+    /// How long to wait after sending to the recipient, used in place of the default if present
+    pub delay: Option<Duration>,
+    /// The segment the recipient belongs to (e.g., `vip`), used to pick their template
+    pub segment: Option<String>,
+    /// The name of the template chosen for the recipient, if one of several was picked
+    pub template: Option<String>,
+    /// The raw CSV record the recipient was read from (empty for JSON)
+    pub record: StringRecord,
+}
+
+impl Recipient {
+    /// Renders the recipient's own message (if any) or else `template` for this recipient.
+    pub fn render(&self, template: &str) -> String {
+        render_message(self.message.as_deref().unwrap_or(template), &self.fields)
+    }
+
+    /// Substitutes `default` for every placeholder whose value is blank (e.g., for a row
+    /// without a name).
+    pub fn fill_blank_fields(&mut self, default: &str) {
+        for value in self.fields.values_mut().filter(|value| value.is_empty()) {
+            *value = default.to_string();
+        }
+    }
+}
+
+/// Options controlling how recipient CSV (or JSON) is parsed.
+pub struct CsvOptions {
+    pub format: RecipientFormat,
+    pub delimiter: u8,
+    /// Whether the first row is a header (rather than a recipient)
+    pub has_header: bool,
+    /// The column (if any) holding each recipient's own message
+    pub message_column: Option<Column>,
+    /// The column (if any) holding the service to send to each recipient with
+    pub service_column: Option<Column>,
+    /// The column (if any) holding how long to wait after sending to each recipient, as a
+    /// human-friendly duration (e.g., `30s`)
+    pub delay_column: Option<Column>,
+    /// The column (if any) holding the segment each recipient belongs to (e.g., `vip`)
+    pub segment_column: Option<Column>,
+    /// The text encoding of the recipients, which are transcoded to UTF-8 as they are read
+    pub encoding: &'static Encoding,
+    /// Whether names are cleaned up with [`normalize_name`] as they are read
+    pub normalize_names: bool,
+    /// Whether every phone number on a vCard is read as a recipient, rather than just the first
+    pub all_numbers: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            format: RecipientFormat::Csv,
+            delimiter: b',',
+            has_header: false,
+            message_column: None,
+            service_column: None,
+            delay_column: None,
+            segment_column: None,
+            encoding: UTF_8,
+            normalize_names: false,
+            all_numbers: false,
+        }
+    }
+}
+
+/// Options controlling how phone numbers are validated and normalized.
+pub struct NumberOptions {
+    /// The country (if any) whose national numbers may be given without a leading `+`
+    pub country: Option<country::Id>,
+    pub min_digits: usize,
+    pub max_digits: usize,
+    /// Whether loading recipients fails on the first invalid number instead of skipping it
+    pub strict: bool,
+    /// Whether letters in vanity numbers (e.g., `1-800-FLOWERS`) are converted to the digits
+    /// they share a phone keypad key with
+    pub vanity: bool,
+}
+
+impl Default for NumberOptions {
+    fn default() -> Self {
+        Self {
+            country: None,
+            min_digits: MIN_NUMBER_LENGTH,
+            max_digits: MAX_NUMBER_LENGTH,
+            strict: false,
+            vanity: false,
+        }
+    }
+}
+
+/// The format of a source of recipients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecipientFormat {
+    Csv,
+    /// An array of objects with a `number` and optional `name`, `attachment`, and other fields
+    Json,
+    /// vCards (e.g., exported from Contacts), with a `TEL` number and optional `FN` name
+    Vcard,
+}
+
+impl RecipientFormat {
+    /// Detects the format of the recipients at `path` by its extension.
+    ///
+    /// `.xlsx` files are read as CSV, since [`open_recipients`] converts them on the fly.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => RecipientFormat::Json,
+            Some("vcf") => RecipientFormat::Vcard,
+            _ => RecipientFormat::Csv,
+        }
+    }
+}
+
+/// A placeholder token and the CSV column holding its per-recipient values.
+#[derive(Clone)]
+pub struct Placeholder {
+    pub token: String,
+    pub column: Column,
+}
+
+/// The message template to send recipients in a given segment (e.g., `vip`).
+#[derive(Clone)]
+pub struct SegmentTemplate {
+    pub segment: String,
+    /// The path to the `.txt` file with the message
+    pub path: String,
+}
+
+/// A reference to a column of the recipients CSV.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    /// The column containing recipient names
+    Name,
+    /// A (0-based) column index
+    Index(usize),
+    /// A column identified by its name in the CSV header
+    Header(String),
+}
+
+/// A recipient excluded from sending, along with the reason why.
+pub struct SkippedRecipient {
+    pub recipient: Recipient,
+    pub reason: String,
+}
+
+impl SkippedRecipient {
+    /// A recipient excluded by one of the filters applied to valid recipients.
+    pub fn new(recipient: Recipient, reason: SkipReason) -> Self {
+        Self {
+            recipient,
+            reason: reason.as_str().to_string(),
+        }
+    }
+}
+
+/// Problems found while validating recipients, gathered so that they can all be reported at
+/// once (e.g., by `--check`) rather than stopping at the first.
+#[derive(Default)]
+pub struct ValidationErrors {
+    problems: Vec<Problem>,
+}
+
+/// A single problem found while validating recipients.
+pub struct Problem {
+    /// The (1-based) line of the recipient concerned, or `None` for a problem with the run as a
+    /// whole (e.g., with the message)
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+impl ValidationErrors {
+    /// Records a problem with the recipient on `line` (or with the run as a whole).
+    pub fn push(&mut self, line: Option<u64>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            line,
+            message: message.into(),
+        });
+    }
+
+    /// Records an error along with its underlying causes, at the CSV line it occurred on (if
+    /// known).
+    pub fn push_error(&mut self, error: &AmsgError) {
+        let (line, mut message) = match error {
+            AmsgError::InvalidRecord { line, reason } => (Some(*line), reason.clone()),
+            AmsgError::Csv { source, .. } => {
+                (source.position().map(|p| p.line()), error.to_string())
+            }
+            _ => (None, error.to_string()),
+        };
+
+        let mut source = std::error::Error::source(error);
+
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+
+        self.push(line, message);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.problems.len()
+    }
+
+    /// The number of distinct lines with at least one problem.
+    pub fn lines(&self) -> usize {
+        self.problems
+            .iter()
+            .filter_map(|problem| problem.line)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// The problems found, ordered by line, with those not tied to a line last.
+    pub fn problems(&self) -> Vec<&Problem> {
+        let mut problems = self.problems.iter().collect::<Vec<_>>();
+        problems.sort_by_key(|problem| (problem.line.is_none(), problem.line));
+        problems
+    }
+}
+
+/// The reasons a recipient with a valid number may still be excluded from sending.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkipReason {
+    Blocked,
+    OutsideAreaCodes,
+    Duplicate,
+    AlreadySent,
+    RecentlySent,
+    EmptyMessage,
+    MissingAttachment,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::Blocked => "Number is on the blocklist",
+            SkipReason::OutsideAreaCodes => "Not in any of the selected area codes",
+            SkipReason::Duplicate => "Duplicate of an earlier recipient",
+            SkipReason::AlreadySent => "Already sent according to checkpoint",
+            SkipReason::RecentlySent => "Sent within the dedupe window according to history",
+            SkipReason::EmptyMessage => "Message is empty after rendering",
+            SkipReason::MissingAttachment => "Attachment does not exist",
+        }
+    }
+}
+
+/// The outcome of processing a single recipient.
+pub enum Status {
+    Sent,
+    Failed,
+    Skipped,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Sent => "sent",
+            Status::Failed => "failed",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+/// A CSV report with one `name,number,status,error` row per recipient, plus the template
+/// chosen for each if several were given and how long each send took if asked.
+pub struct Report {
+    writer: Writer<fs::File>,
+    mask_numbers: bool,
+    templates: bool,
+    latency: bool,
+}
+
+impl Report {
+    /// Creates a report at `path`, with numbers masked by [`mask_number`] if `mask_numbers`, a
+    /// `template` column if `templates`, and a `latency_ms` column if `latency`.
+    pub fn create(path: &str, mask_numbers: bool, templates: bool, latency: bool) -> Result<Self> {
+        let mut writer = Writer::from_path(path).map_err(AmsgError::csv(format!(
+            "Failed to create report at {}",
+            path
+        )))?;
+        let mut header = vec!["name", "number", "status", "error"];
+
+        if templates {
+            header.push("template");
+        }
+
+        if latency {
+            header.push("latency_ms");
+        }
+
+        writer
+            .write_record(header)
+            .map_err(AmsgError::csv("Failed to write report header"))?;
+
+        Ok(Self {
+            writer,
+            mask_numbers,
+            templates,
+            latency,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        recipient: &Recipient,
+        status: Status,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.record_with_latency(recipient, status, error, None)
+    }
+
+    /// Records a recipient like [`Self::record`], along with how long sending to them took
+    /// (which is left blank for recipients never sent to).
+    pub fn record_with_latency(
+        &mut self,
+        recipient: &Recipient,
+        status: Status,
+        error: Option<&str>,
+        latency: Option<Duration>,
+    ) -> Result<()> {
+        let mut number = recipient.number.clone();
+        let mut error = error.map(str::trim).unwrap_or_default().to_string();
+
+        // Errors (e.g., for invalid numbers) may quote the number too
+        if self.mask_numbers && !number.is_empty() {
+            let masked = mask_number(&number);
+            error = error.replace(&number, &masked);
+            number = masked;
+        }
+
+        let mut record = vec![
+            recipient.name.as_deref().unwrap_or_default(),
+            &number,
+            status.as_str(),
+            &error,
+        ];
+
+        if self.templates {
+            record.push(recipient.template.as_deref().unwrap_or_default());
+        }
+
+        let latency = latency
+            .map(|latency| latency.as_millis().to_string())
+            .unwrap_or_default();
+
+        if self.latency {
+            record.push(&latency);
+        }
+
+        self.writer
+            .write_record(record)
+            .map_err(AmsgError::csv("Failed to write report record"))?;
+
+        // Flush each row so the audit trail survives an interrupted run
+        self.writer
+            .flush()
+            .map_err(AmsgError::io("Failed to flush report"))?;
+
+        Ok(())
+    }
+}
+
+/// Newline-delimited JSON events, one written (and flushed) as each recipient is sent to or
+/// skipped, for other programs to follow a run as it happens.
+///
+/// Each event has `event` (`send` or `skip`), `number`, `name`, `status`, `timestamp`, and
+/// `error` fields, with numbers masked if enabled by [`set_number_masking`]. A final `finish`
+/// event gives the number of recipients sent to, failed, and skipped.
+pub struct EventStream<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> EventStream<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Writes the outcome for a recipient, with the error (or reason for skipping) if any.
+    pub fn record(
+        &mut self,
+        recipient: &Recipient,
+        status: Status,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let event = match status {
+            Status::Skipped => "skip",
+            Status::Sent | Status::Failed => "send",
+        };
+
+        self.write(serde_json::json!({
+            "event": event,
+            "number": DisplayNumber(&recipient.number).to_string(),
+            "name": recipient.name,
+            "status": status.as_str(),
+            "timestamp": Local::now().to_rfc3339(),
+            "error": error.map(str::trim),
+        }))
+    }
+
+    pub fn finish(mut self, sent: usize, failed: usize, skipped: usize) -> Result<()> {
+        self.write(serde_json::json!({
+            "event": "finish",
+            "sent": sent,
+            "failed": failed,
+            "skipped": skipped,
+            "timestamp": Local::now().to_rfc3339(),
+        }))
+    }
+
+    fn write(&mut self, event: Value) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &event)
+            .map_err(AmsgError::json("Failed to write event"))?;
+
+        // Flush each event so that consumers see it as soon as the send completes
+        self.writer
+            .write_all(b"\n")
+            .and_then(|()| self.writer.flush())
+            .map_err(AmsgError::io("Failed to write event"))
+    }
+}
+
+/// The spread of how long sends took over a run, for tuning the delay between them.
+#[derive(Debug, PartialEq)]
+pub struct SendLatency {
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl SendLatency {
+    /// Summarizes the durations of a run's sends, or `None` if there were none.
+    ///
+    /// Percentiles are nearest-rank, so each is one of the durations given.
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut samples = samples.to_vec();
+        samples.sort_unstable();
+
+        // The smallest sample with at least `percent`% of the samples at or below it
+        let percentile = |percent: usize| samples[(samples.len() * percent).div_ceil(100) - 1];
+
+        Some(Self {
+            min: samples[0],
+            median: percentile(50),
+            p95: percentile(95),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+/// A summary of a finished run, as POSTed to a webhook by [`post_webhook`].
+#[cfg(feature = "webhook")]
+#[derive(Debug, Default, serde::Serialize)]
+pub struct RunSummary {
+    pub sent: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub duration_secs: u64,
+    pub dry_run: bool,
+    pub interrupted: bool,
+    pub failures: Vec<FailedSend>,
+}
+
+/// A recipient whose message failed to send, with the number masked if enabled by
+/// [`set_number_masking`].
+#[cfg(feature = "webhook")]
+#[derive(Debug, serde::Serialize)]
+pub struct FailedSend {
+    pub name: Option<String>,
+    pub number: String,
+    pub error: String,
+}
+
+#[cfg(feature = "webhook")]
+impl FailedSend {
+    pub fn new(recipient: &Recipient, error: &AmsgError) -> Self {
+        Self {
+            name: recipient.name.clone(),
+            number: DisplayNumber(&recipient.number).to_string(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// POSTs `summary` as JSON to `url`, failing if the request takes longer than `timeout` or
+/// the server does not respond with a success status.
+#[cfg(feature = "webhook")]
+pub fn post_webhook(url: &str, summary: &RunSummary, timeout: Duration) -> Result<()> {
+    let agent = ureq::Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .build()
+        .new_agent();
+
+    agent
+        .post(url)
+        .send_json(summary)
+        .map(drop)
+        .map_err(AmsgError::http(format!(
+            "Failed to POST summary to {}",
+            url
+        )))
+}
+
+/// A CSV of recipients whose messages failed to send, written in the same format as the
+/// recipients CSV so that it can be passed back in to retry them.
+pub struct FailureLog {
+    writer: Writer<fs::File>,
+}
+
+impl FailureLog {
+    pub fn create(path: &str, options: &CsvOptions, header: Option<&StringRecord>) -> Result<Self> {
+        let mut writer = WriterBuilder::new()
+            .delimiter(options.delimiter)
+            .from_path(path)
+            .map_err(AmsgError::csv(format!(
+                "Failed to create failures CSV at {}",
+                path
+            )))?;
+
+        if let Some(header) = header {
+            writer
+                .write_record(header)
+                .map_err(AmsgError::csv("Failed to write failures CSV header"))?;
+        }
+
+        Ok(Self { writer })
+    }
+
+    pub fn record(&mut self, recipient: &Recipient) -> Result<()> {
+        self.writer
+            .write_record(&recipient.record)
+            .map_err(AmsgError::csv("Failed to write failures CSV record"))?;
+        self.writer
+            .flush()
+            .map_err(AmsgError::io("Failed to flush failures CSV"))?;
+
+        Ok(())
+    }
+}
+
+/// The fully rendered messages of a dry run, written out for review as JSON (if the path ends
+/// in `.json`) or else as plain text.
+pub struct DryRunOutput {
+    writer: BufWriter<File>,
+    json: bool,
+    written: usize,
+}
+
+impl DryRunOutput {
+    pub fn create(path: &str) -> Result<Self> {
+        let json = Path::new(path).extension().is_some_and(|ext| ext == "json");
+        let file = File::create(path).map_err(AmsgError::io(format!(
+            "Failed to create dry-run output at {}",
+            path
+        )))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            json,
+            written: 0,
+        })
+    }
+
+    /// Writes the message rendered for a recipient, along with the service it would go via.
+    pub fn record(&mut self, recipient: &Recipient, service: &str, message: &str) -> Result<()> {
+        let written = if self.json {
+            let entry = serde_json::json!({
+                "line": recipient.line,
+                "name": recipient.name,
+                "number": recipient.number,
+                "service": service,
+                "attachment": recipient.attachment,
+                "message": message,
+            });
+            let separator = if self.written == 0 { "[\n  " } else { ",\n  " };
+
+            self.writer
+                .write_all(separator.as_bytes())
+                .and_then(|()| serde_json::to_writer(&mut self.writer, &entry).map_err(Into::into))
+        } else {
+            self.write_text(recipient, service, message)
+        };
+
+        written.map_err(AmsgError::io("Failed to write dry-run output"))?;
+        self.written += 1;
+
+        Ok(())
+    }
+
+    fn write_text(
+        &mut self,
+        recipient: &Recipient,
+        service: &str,
+        message: &str,
+    ) -> io::Result<()> {
+        match &recipient.name {
+            Some(name) => writeln!(self.writer, "To: {} ({})", name, recipient.number)?,
+            None => writeln!(self.writer, "To: {}", recipient.number)?,
+        }
+
+        writeln!(self.writer, "Line: {}", recipient.line)?;
+        writeln!(self.writer, "Service: {}", service)?;
+
+        if let Some(attachment) = &recipient.attachment {
+            writeln!(self.writer, "Attachment: {}", attachment)?;
+        }
+
+        writeln!(self.writer, "\n{}\n\n---\n", message.trim_end())
+    }
+
+    /// Closes the JSON array (if any) and flushes everything written to disk.
+    pub fn finish(mut self) -> Result<()> {
+        let end = match (self.json, self.written) {
+            (false, _) => "",
+            (true, 0) => "[]\n",
+            (true, _) => "\n]\n",
+        };
+
+        self.writer
+            .write_all(end.as_bytes())
+            .and_then(|()| self.writer.flush())
+            .map_err(AmsgError::io("Failed to write dry-run output"))
+    }
+}
+
+/// Checks that `path` is an existing file with one of the given extensions.
+///
+/// Extensions may span more than one dot (e.g., `csv.gz`), in which case the whole suffix must
+/// match. Apple Numbers documents are recognized so as to explain how to export them instead.
+pub fn validate_file_path(path: &str, extensions: &[&str]) -> Result<()> {
+    if path.is_empty() {
+        return Err(AmsgError::InvalidPath(String::from(
+            "Path must not be empty",
+        )));
+    }
+
+    let path_obj = Path::new(path);
+
+    if !path_obj.exists() {
+        return Err(AmsgError::FileNotFound(path.to_string()));
+    }
+
+    let file_name = path_obj.file_name().and_then(|name| name.to_str());
+    let has_extension = |extension: &str| {
+        file_name
+            .and_then(|name| name.strip_suffix(extension))
+            .and_then(|stem| stem.strip_suffix('.'))
+            .is_some_and(|stem| !stem.is_empty())
+    };
+
+    // Checked before it is found not to be a file, as Numbers may save documents as packages
+    if has_extension("numbers") {
+        return Err(AmsgError::InvalidPath(format!(
+            "{} is an Apple Numbers document, which cannot be read directly; export it first \
+             with File > Export To > CSV (or Excel) in Numbers, then pass the exported file",
+            path
+        )));
+    }
+
+    if !path_obj.is_file() {
+        return Err(AmsgError::InvalidPath(format!(
+            "{} exists but is not a file",
+            path
+        )));
+    }
+
+    if !extensions.iter().any(|&e| has_extension(e)) {
+        return Err(AmsgError::InvalidPath(format!(
+            "File {} does not end with .{}",
+            path,
+            extensions.join(" or .")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Parses a non-negative, human-friendly duration such as `500ms`, `2s`, or `1m`.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+
+    if duration.starts_with('-') {
+        return Err(AmsgError::InvalidArgument(format!(
+            "Duration must not be negative: {}",
+            duration
+        )));
+    }
+
+    humantime::parse_duration(duration)
+        .map_err(|e| AmsgError::InvalidArgument(format!("Invalid duration {}: {}", duration, e)))
+}
+
+/// Parses an RFC 3339 datetime such as `2024-06-01T09:00:00-04:00` (e.g., for scheduling).
+pub fn parse_datetime(datetime: &str) -> Result<DateTime<Local>> {
+    let datetime = datetime.trim();
+
+    DateTime::parse_from_rfc3339(datetime)
+        .map(|datetime| datetime.with_timezone(&Local))
+        .map_err(|e| {
+            AmsgError::InvalidArgument(format!("Invalid RFC 3339 datetime {}: {}", datetime, e))
+        })
+}
+
+/// A SQLite database of every message sent across runs, recording when each number was sent
+/// to (and for which campaign, if named) so that later runs can avoid texting them again.
+///
+/// Numbers are stored as SHA-256 hashes rather than in the clear.
+#[cfg(feature = "history")]
+pub struct History {
+    connection: rusqlite::Connection,
+    campaign: Option<String>,
+    // Sends recorded after the database was opened are ignored by `sent_within`, so that
+    // (like a checkpoint) recording sends does not affect which recipients are selected
+    last_id: i64,
+}
+
+#[cfg(feature = "history")]
+impl History {
+    /// Opens (or creates) the history at `path`, recording sends under `campaign` (if any).
+    pub fn open(path: &str, campaign: Option<&str>) -> Result<Self> {
+        let sqlite = |context: &str| AmsgError::sqlite(format!("{} {}", context, path));
+        let connection =
+            rusqlite::Connection::open(path).map_err(sqlite("Failed to open history at"))?;
+
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS sends (
+                    id INTEGER PRIMARY KEY,
+                    number_hash TEXT NOT NULL,
+                    sent_at INTEGER NOT NULL,
+                    campaign TEXT
+                );
+                CREATE INDEX IF NOT EXISTS sends_by_number ON sends (number_hash, sent_at);",
+            )
+            .map_err(sqlite("Failed to set up history at"))?;
+
+        let last_id = connection
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM sends", [], |row| {
+                row.get(0)
+            })
+            .map_err(sqlite("Failed to read history at"))?;
+
+        Ok(Self {
+            connection,
+            campaign: campaign.map(String::from),
+            last_id,
+        })
+    }
+
+    /// Whether `number` was sent to within `window` of now in an earlier run.
+    pub fn sent_within(&self, number: &str, window: Duration) -> Result<bool> {
+        let window = i64::try_from(window.as_secs()).unwrap_or(i64::MAX);
+        let since = Local::now().timestamp().saturating_sub(window);
+
+        self.connection
+            .query_row(
+                "SELECT EXISTS (
+                    SELECT 1 FROM sends WHERE number_hash = ?1 AND sent_at >= ?2 AND id <= ?3
+                )",
+                rusqlite::params![hash_number(number), since, self.last_id],
+                |row| row.get(0),
+            )
+            .map_err(AmsgError::sqlite("Failed to read history"))
+    }
+
+    pub fn record(&mut self, number: &str) -> Result<()> {
+        self.connection
+            .execute(
+                "INSERT INTO sends (number_hash, sent_at, campaign) VALUES (?1, ?2, ?3)",
+                rusqlite::params![hash_number(number), Local::now().timestamp(), self.campaign],
+            )
+            .map(drop)
+            .map_err(AmsgError::sqlite("Failed to record send in history"))
+    }
+}
+
+#[cfg(feature = "history")]
+fn hash_number(number: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(number.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The numbers already sent to in a run, persisted as a JSON array so the run can be resumed.
+pub struct Checkpoint {
+    path: PathBuf,
+    sent: Vec<String>,
+    seen: HashSet<String>,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint at `path`, starting afresh if it does not yet exist.
+    pub fn load(path: &str) -> Result<Self> {
+        let sent: Vec<String> = if Path::new(path).exists() {
+            let contents = fs::read_to_string(path).map_err(AmsgError::io(format!(
+                "Failed to read checkpoint from {}",
+                path
+            )))?;
+            serde_json::from_str(&contents).map_err(AmsgError::json(format!(
+                "Failed to parse checkpoint from {}",
+                path
+            )))?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            seen: sent.iter().cloned().collect(),
+            sent,
+        })
+    }
+
+    /// Whether `number` was already sent to.
+    pub fn is_sent(&self, number: &str) -> bool {
+        self.seen.contains(number)
+    }
+
+    /// Records a successful send, saving the checkpoint immediately.
+    pub fn record(&mut self, number: &str) -> Result<()> {
+        if !self.seen.insert(number.to_string()) {
+            return Ok(());
+        }
+
+        self.sent.push(number.to_string());
+
+        // Write to a temporary file first so that a crash mid-write cannot corrupt the checkpoint
+        let temp_path = self.path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(&self.sent)
+            .map_err(AmsgError::json("Failed to serialize checkpoint"))?;
+        fs::write(&temp_path, contents).map_err(AmsgError::io(format!(
+            "Failed to write checkpoint to {}",
+            temp_path.display()
+        )))?;
+        fs::rename(&temp_path, &self.path).map_err(AmsgError::io(format!(
+            "Failed to save checkpoint to {}",
+            self.path.display()
+        )))?;
+
+        Ok(())
+    }
+}
+
+/// Numbers (or Apple ID emails) that must never be sent to, such as those that have opted out.
+pub struct Blocklist {
+    numbers: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Loads a blocklist with one number per line, normalized like recipients' numbers.
+    ///
+    /// Blank lines and lines starting with `#` are ignored, as is anything after a comma (so
+    /// the first column of a CSV file may be used directly). Any invalid number is an error,
+    /// lest an opted-out recipient slip through.
+    pub fn load(path: &str, options: &NumberOptions) -> Result<Self> {
+        let contents = fs::read_to_string(path).map_err(AmsgError::io(format!(
+            "Failed to read blocklist from {}",
+            path
+        )))?;
+        let mut numbers = HashSet::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let number = line.split(',').next().unwrap_or_default().trim();
+
+            if number.is_empty() || number.starts_with('#') {
+                continue;
+            }
+
+            let normalized = if is_email(number) {
+                number.to_lowercase()
+            } else {
+                process_number(number, options).map_err(|e| {
+                    AmsgError::InvalidRecipients(format!(
+                        "Invalid number on line {} of blocklist {}: {}",
+                        i + 1,
+                        path,
+                        e
+                    ))
+                })?
+            };
+
+            numbers.insert(normalized.trim_start_matches('+').to_string());
+        }
+
+        Ok(Self { numbers })
+    }
+
+    /// Whether `number` (as normalized for a recipient) is blocked.
+    ///
+    /// Numbers are compared ignoring any leading `+`, as in [`Duplicates::is_duplicate`].
+    pub fn is_blocked(&self, number: &str) -> bool {
+        self.numbers.contains(number.trim_start_matches('+'))
+    }
+}
+
+/// Spaces out sends so that none starts within `delay` of another starting or finishing.
+///
+/// Without concurrency, this amounts to waiting `delay` after each send before the next.
+pub struct RateLimiter {
+    delay: Duration,
+    jitter: f64,
+    batch: Option<(usize, Duration)>,
+    // The longest any delay may grow to when adapting to failures, and how much longer than
+    // usual each delay currently is
+    adaptive: Option<Duration>,
+    backoff: Mutex<Duration>,
+    next: Mutex<Instant>,
+    started: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            jitter: 0.0,
+            batch: None,
+            adaptive: None,
+            backoff: Mutex::new(Duration::ZERO),
+            next: Mutex::new(Instant::now()),
+            started: AtomicUsize::new(0),
+        }
+    }
+
+    /// Randomizes each delay uniformly within `delay * (1 ± jitter)`, with `jitter` in `[0, 1]`.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Waits at least `pause` (rather than the usual delay) after every `size` sends.
+    pub fn with_batches(mut self, size: usize, pause: Duration) -> Self {
+        self.batch = (size > 0).then_some((size, pause));
+        self
+    }
+
+    /// Lengthens delays after failed sends (e.g., when throttled), up to `max`, and shortens
+    /// them again after successful ones, as reported with [`Self::adapt`].
+    pub fn with_adaptive(mut self, max: Duration) -> Self {
+        self.adaptive = Some(max);
+        self
+    }
+
+    /// Adapts later delays to how a send went, if adaptive delays are enabled: each failure
+    /// doubles the extra delay (starting from the usual delay, or a second if there is none),
+    /// while each success takes a quarter of it off again.
+    pub fn adapt(&self, failed: bool) {
+        let Some(max) = self.adaptive else {
+            return;
+        };
+
+        let mut backoff = self.backoff.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = *backoff;
+
+        *backoff = if failed {
+            (previous * 2)
+                .max(self.delay.max(ADAPTIVE_MIN_BACKOFF))
+                .min(max.saturating_sub(self.delay))
+        } else if previous < ADAPTIVE_MIN_BACKOFF / 100 {
+            Duration::ZERO
+        } else {
+            previous * 3 / 4
+        };
+
+        let delay = humantime::format_duration(self.delay + *backoff);
+
+        if *backoff > previous {
+            debug!(delay = %delay, "Increasing delay after a failed send");
+        } else if *backoff < previous {
+            debug!(delay = %delay, "Decreasing delay after a successful send");
+        }
+    }
+
+    fn next_delay(&self, delay: Option<Duration>) -> Duration {
+        let delay = delay.unwrap_or(self.delay);
+
+        let delay = match self.adaptive {
+            Some(max) => {
+                let backoff = *self.backoff.lock().unwrap_or_else(PoisonError::into_inner);
+                (delay + backoff).min(max.max(delay))
+            }
+            None => delay,
+        };
+
+        if self.jitter == 0.0 {
+            return delay;
+        }
+
+        delay.mul_f64(rand::random_range(1.0 - self.jitter..=1.0 + self.jitter))
+    }
+
+    /// Blocks until a send may start.
+    pub fn acquire(&self) {
+        self.acquire_with_delay(None);
+    }
+
+    /// Blocks until a send may start like [`Self::acquire`], but waits `delay` (if given)
+    /// rather than the usual delay before the next.
+    pub fn acquire_with_delay(&self, delay: Option<Duration>) {
+        // Sleep while holding the lock so that waiting senders are let through one at a time
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        let started = self.started.fetch_add(1, Ordering::Relaxed);
+
+        if let Some((size, pause)) = self.batch
+            && started > 0
+            && started.is_multiple_of(size)
+        {
+            info!(
+                batch = started / size,
+                pause = %humantime::format_duration(pause),
+                "Finished batch, pausing before the next"
+            );
+            *next = (*next).max(Instant::now() + pause);
+        }
+
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+        *next = Instant::now() + self.next_delay(delay);
+    }
+
+    /// Records that a send has finished.
+    pub fn release(&self) {
+        self.release_with_delay(None);
+    }
+
+    /// Records that a send started with [`Self::acquire_with_delay`] has finished.
+    pub fn release_with_delay(&self, delay: Option<Duration>) {
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        *next = (*next).max(Instant::now() + self.next_delay(delay));
+    }
+}
+
+/// Estimates how much longer a run will take from how long its first `done` of `total` sends
+/// took, or `None` before any have finished.
+///
+/// The estimate is based on the rate at which sends finish, so it accounts for sending time,
+/// delays, and concurrent sends alike.
+pub fn estimate_remaining(elapsed: Duration, done: usize, total: usize) -> Option<Duration> {
+    let done = u32::try_from(done).ok().filter(|&done| done > 0)?;
+    let remaining = u32::try_from(total.saturating_sub(done as usize)).ok()?;
+
+    Some(elapsed / done * remaining)
+}
+
+/// Parses a placeholder given either as a bare token (substituted with the recipient's name)
+/// or as `TOKEN=COLUMN` (substituted with the value of `COLUMN`, either a 1-based index or a
+/// header name).
+pub fn parse_placeholder(placeholder: &str) -> Result<Placeholder> {
+    let (token, column) = match placeholder.rsplit_once('=') {
+        Some((token, column)) => {
+            let column = parse_column(column).map_err(|e| {
+                AmsgError::InvalidArgument(format!("{} in placeholder {}", e, placeholder))
+            })?;
+
+            (token, column)
+        }
+        None => (placeholder, Column::Name),
+    };
+
+    if token.is_empty() {
+        return Err(AmsgError::InvalidArgument(String::from(
+            "Placeholder token must not be empty",
+        )));
+    }
+
+    Ok(Placeholder {
+        token: token.to_string(),
+        column,
+    })
+}
+
+/// Parses a column given either as a 1-based index or as a header name.
+pub fn parse_column(column: &str) -> Result<Column> {
+    let column = column.trim();
+
+    match column.parse::<usize>() {
+        Ok(0) => Err(AmsgError::InvalidArgument(String::from(
+            "Column indices start at 1",
+        ))),
+        Ok(index) => Ok(Column::Index(index - 1)),
+        Err(_) if column.is_empty() => Err(AmsgError::InvalidArgument(String::from(
+            "Column must not be empty",
+        ))),
+        Err(_) => Ok(Column::Header(column.to_string())),
+    }
+}
+
+/// Parses an ISO 3166-1 alpha-2 country code (e.g., `US` or `gb`).
+pub fn parse_country(code: &str) -> Result<country::Id> {
+    code.trim().to_ascii_uppercase().parse().map_err(|_| {
+        AmsgError::InvalidArgument(format!("Invalid ISO 3166-1 alpha-2 country code: {}", code))
+    })
+}
+
+/// Parses an area code (or any other leading digits of national numbers), ignoring spaces,
+/// hyphens, and parentheses (e.g., `(415)`).
+pub fn parse_area_code(code: &str) -> Result<String> {
+    let digits = code
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')'))
+        .collect::<String>();
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(AmsgError::InvalidArgument(format!(
+            "Area code must consist of digits: {}",
+            code
+        )));
+    }
+
+    Ok(digits)
+}
+
+/// Parses a CSV delimiter, which must be a single ASCII character (with `\t` denoting a tab).
+pub fn parse_delimiter(delimiter: &str) -> Result<u8> {
+    // Literal tabs are awkward to type in most shells, so accept the escape sequence too
+    let delimiter = if delimiter == "\\t" { "\t" } else { delimiter };
+
+    match delimiter.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(AmsgError::InvalidArgument(format!(
+            "Delimiter must be a single ASCII character: {:?}",
+            delimiter
+        ))),
+    }
+}
+
+/// Parses the label of a text encoding (e.g., `utf-8` or `latin1`).
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| AmsgError::InvalidArgument(format!("Unknown encoding: {:?}", label)))
+}
+
+/// Parses a [`SegmentTemplate`] given as `SEGMENT=PATH` (e.g., `vip=vip.txt`).
+pub fn parse_segment_template(mapping: &str) -> Result<SegmentTemplate> {
+    let Some((segment, path)) = mapping.split_once('=') else {
+        return Err(AmsgError::InvalidArgument(format!(
+            "Invalid template {:?} (expected SEGMENT=PATH, e.g., vip=vip.txt)",
+            mapping
+        )));
+    };
+
+    if segment.trim().is_empty() || path.is_empty() {
+        return Err(AmsgError::InvalidArgument(format!(
+            "Template {:?} must give both a segment and a path",
+            mapping
+        )));
+    }
+
+    Ok(SegmentTemplate {
+        segment: segment.trim().to_string(),
+        path: path.to_string(),
+    })
+}
+
+/// Parses the weights of a [`Split`] between two or more messages, separated by slashes (e.g.,
+/// `70/30` or `1/1/2`).
+pub fn parse_split(split: &str) -> Result<Split> {
+    let invalid = || {
+        AmsgError::InvalidArgument(format!(
+            "Invalid split {:?} (expected two or more positive weights, e.g., 70/30)",
+            split
+        ))
+    };
+
+    let weights = split
+        .split('/')
+        .map(|weight| match weight.trim().parse::<u32>() {
+            Ok(weight) if weight > 0 => Ok(weight),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if weights.len() < 2 {
+        return Err(invalid());
+    }
+
+    // Weights are summed when choosing, which must not overflow
+    weights
+        .iter()
+        .try_fold(0u32, |total, &weight| total.checked_add(weight))
+        .ok_or_else(invalid)?;
+
+    Ok(Split(weights))
+}
+
+/// Checks that `service` is a non-empty, purely alphabetic service name.
+pub fn validate_service(service: &str) -> Result<()> {
+    if service.is_empty() {
+        return Err(AmsgError::InvalidArgument(String::from(
+            "Service name must not be empty",
+        )));
+    }
+
+    if !service.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AmsgError::InvalidArgument(format!(
+            "Invalid (non-alphabetic) characters in service name: {}",
+            service
+        )));
+    }
+
+    Ok(())
+}
+
+/// Opens `path` (or stdin, if `path` is [`STDIN_PATH`]) as a source of CSV recipients.
+///
+/// The first sheet of an `.xlsx` file is converted to CSV (delimited per `options`), so that
+/// it follows the same column conventions, while `.gz` files are decompressed as they are read.
+pub fn open_recipients(path: &str, options: &CsvOptions) -> Result<Box<dyn Read + Send>> {
+    if path == STDIN_PATH {
+        return Ok(Box::new(io::stdin()));
+    }
+
+    if Path::new(path).extension().is_some_and(|ext| ext == "xlsx") {
+        return Ok(Box::new(io::Cursor::new(read_xlsx(path, options)?)));
+    }
+
+    let file =
+        File::open(path).map_err(AmsgError::io(format!("Failed to read CSV from {}", path)))?;
+
+    if Path::new(path).extension().is_some_and(|ext| ext == "gz") {
+        return Ok(Box::new(MultiGzDecoder::new(BufReader::new(file))));
+    }
+
+    Ok(Box::new(file))
+}
+
+fn read_xlsx(path: &str, options: &CsvOptions) -> Result<Vec<u8>> {
+    let mut workbook: Xlsx<_> = open_workbook(path)
+        .map_err(AmsgError::xlsx(format!("Failed to open workbook {}", path)))?;
+    let sheet = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| AmsgError::InvalidRecipients(format!("Workbook {} has no sheets", path)))?
+        .map_err(AmsgError::xlsx(format!(
+            "Failed to read first sheet of {}",
+            path
+        )))?;
+
+    let mut writer = WriterBuilder::new()
+        .delimiter(options.delimiter)
+        .from_writer(Vec::new());
+
+    for row in sheet.rows() {
+        // Numeric cells (e.g., unformatted phone numbers) are written without a fractional part
+        writer
+            .write_record(row.iter().map(Data::to_string))
+            .map_err(AmsgError::csv("Failed to convert sheet row to CSV"))?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| AmsgError::io("Failed to convert sheet to CSV")(e.into_error()))
+}
+
+/// Recipients loaded from CSV, along with the header row (if any).
+pub struct LoadedRecipients {
+    pub recipients: Vec<Recipient>,
+    pub skipped: Vec<SkippedRecipient>,
+    pub header: Option<StringRecord>,
+}
+
+/// A recipient read by a [`RecipientStream`], either with a valid number or skipped.
+pub enum LoadedRecipient {
+    Valid(Recipient),
+    Invalid(SkippedRecipient),
+}
+
+/// Reads recipients as CSV one at a time as the stream is advanced, separating those with
+/// valid numbers from those skipped.
+///
+/// CSV records are read from `source` only as needed, so memory use stays flat however many
+/// recipients there are. JSON arrays, on the other hand, are parsed in full up front.
+pub fn stream_recipients<'a, R: Read + Send + 'a>(
+    source: R,
+    options: &CsvOptions,
+    placeholders: &[Placeholder],
+    number_options: &'a NumberOptions,
+) -> Result<RecipientStream<'a>> {
+    let (header, raw) = read_source(source, options, placeholders)?;
+
+    Ok(RecipientStream {
+        header,
+        raw,
+        number_options,
+        quiet: false,
+    })
+}
+
+type RawRecipients<'a> = Box<dyn Iterator<Item = Result<Recipient>> + Send + 'a>;
+
+/// Recipients read one at a time, with their numbers validated and normalized.
+///
+/// An item is an error only if the source cannot be read or (with [`NumberOptions::strict`])
+/// a number is invalid.
+pub struct RecipientStream<'a> {
+    pub header: Option<StringRecord>,
+    raw: RawRecipients<'a>,
+    number_options: &'a NumberOptions,
+    quiet: bool,
+}
+
+impl RecipientStream<'_> {
+    /// Skips recipients without logging a warning for each (e.g., when rereading a source).
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    /// Reads the remaining recipients, separating those with valid numbers from those skipped.
+    pub fn load(mut self) -> Result<LoadedRecipients> {
+        let header = self.header.take();
+        let mut recipients = Vec::new();
+        let mut skipped = Vec::new();
+
+        for loaded in self {
+            match loaded? {
+                LoadedRecipient::Valid(recipient) => recipients.push(recipient),
+                LoadedRecipient::Invalid(s) => skipped.push(s),
+            }
+        }
+
+        Ok(LoadedRecipients {
+            recipients,
+            skipped,
+            header,
+        })
+    }
+}
+
+impl Iterator for RecipientStream<'_> {
+    type Item = Result<LoadedRecipient>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r = match self.raw.next()? {
+            Ok(r) => r,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let processed = match r.service.as_deref().map(validate_service) {
+            Some(Err(e)) => Err(e),
+            // Apple IDs may be email addresses, which bypass phone number normalization
+            _ if is_email(&r.number) => Ok(r.number.to_lowercase()),
+            _ => process_number(&r.number, self.number_options),
+        };
+
+        Some(match processed {
+            Ok(processed_number) => Ok(LoadedRecipient::Valid(Recipient {
+                number: processed_number,
+                ..r
+            })),
+            Err(e) if self.number_options.strict => Err(AmsgError::InvalidRecord {
+                line: r.line,
+                reason: e.to_string(),
+            }),
+            Err(e) => {
+                if !self.quiet {
+                    warn!(
+                        recipient = r.name.as_deref(),
+                        number = %DisplayNumber(&r.number),
+                        status = Status::Skipped.as_str(),
+                        error = %e,
+                        "Skipping invalid recipient on line {}",
+                        r.line
+                    );
+                }
+
+                Ok(LoadedRecipient::Invalid(SkippedRecipient {
+                    recipient: r,
+                    reason: e.to_string(),
+                }))
+            }
+        })
+    }
+}
+
+/// Tracks the numbers seen so far to catch duplicates one recipient at a time.
+///
+/// Unlike the recipients themselves, every distinct number seen is kept in memory.
+#[derive(Default)]
+pub struct Duplicates {
+    seen: HashSet<String>,
+}
+
+impl Duplicates {
+    /// Whether `number` duplicates one seen before, noting it as seen if not.
+    ///
+    /// Numbers are compared ignoring any leading `+`, so `+12345678910` and `12345678910`
+    /// match.
+    pub fn is_duplicate(&mut self, number: &str) -> bool {
+        !self.seen.insert(number.trim_start_matches('+').to_string())
+    }
+}
+
+/// The filters that may exclude a recipient with a valid number, applied one recipient at a
+/// time in the order of their fields.
+#[derive(Default)]
+pub struct RecipientFilters {
+    pub blocklist: Option<Blocklist>,
+    /// Recipients outside every one of these are excluded, unless there are none
+    pub area_codes: Vec<String>,
+    /// Every distinct number seen is kept to catch duplicates, unless they are allowed
+    pub duplicates: Option<Duplicates>,
+    pub checkpoint: Option<Checkpoint>,
+    /// Recipients sent to within the window in an earlier run are excluded
+    #[cfg(feature = "history")]
+    pub history: Option<(History, Duration)>,
+}
+
+impl RecipientFilters {
+    /// The reason the first filter to exclude `number` does so, if any does.
+    pub fn skip_reason(&mut self, number: &str) -> Result<Option<SkipReason>> {
+        if self
+            .blocklist
+            .as_ref()
+            .is_some_and(|blocklist| blocklist.is_blocked(number))
+        {
+            return Ok(Some(SkipReason::Blocked));
+        }
+
+        if !self.area_codes.is_empty() && !in_area_codes(number, &self.area_codes) {
+            return Ok(Some(SkipReason::OutsideAreaCodes));
+        }
+
+        if self
+            .duplicates
+            .as_mut()
+            .is_some_and(|duplicates| duplicates.is_duplicate(number))
+        {
+            return Ok(Some(SkipReason::Duplicate));
+        }
+
+        if self
+            .checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.is_sent(number))
+        {
+            return Ok(Some(SkipReason::AlreadySent));
+        }
+
+        #[cfg(feature = "history")]
+        if let Some((history, window)) = &self.history
+            && history.sent_within(number, *window)?
+        {
+            return Ok(Some(SkipReason::RecentlySent));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Randomly reorders recipients, reproducibly if a `seed` is given.
+pub fn shuffle_recipients(recipients: &mut [Recipient], seed: Option<u64>) {
+    match seed {
+        Some(seed) => recipients.shuffle(&mut StdRng::seed_from_u64(seed)),
+        None => recipients.shuffle(&mut rand::rng()),
+    }
+}
+
+/// Weights by which recipients are split between several messages (e.g., for A/B testing).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Split(Vec<u32>);
+
+impl Split {
+    /// An even split between `count` messages.
+    pub fn even(count: usize) -> Self {
+        Self(vec![1; count])
+    }
+
+    /// The weight of each message, in order.
+    pub fn weights(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Picks the index of the message to send `number`, in proportion to the weights.
+    ///
+    /// The choice depends only on the number and `seed`, so a recipient gets the same message
+    /// on every run (and in every pass over the recipients).
+    pub fn choose(&self, number: &str, seed: u64) -> usize {
+        let total = self.0.iter().map(|&weight| u64::from(weight)).sum::<u64>();
+        let mut point = stable_hash(number, seed) % total;
+
+        for (i, &weight) in self.0.iter().enumerate() {
+            match point.checked_sub(u64::from(weight)) {
+                Some(rest) => point = rest,
+                None => return i,
+            }
+        }
+
+        unreachable!("the point falls within the total of the weights")
+    }
+}
+
+// FNV-1a, whose output (unlike that of `std`'s hashers) is the same across Rust versions,
+// followed by the SplitMix64 finalizer so that the low bits taken modulo the total are well mixed
+fn stable_hash(text: &str, seed: u64) -> u64 {
+    let mut hash = seed
+        .to_le_bytes()
+        .iter()
+        .chain(text.as_bytes())
+        .fold(0xcbf29ce484222325u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Whether the national number of `number` starts with any of `area_codes`.
+///
+/// Numbers in E.164 form (i.e., with a leading `+`) are compared without their country code,
+/// so `+14155550100` matches `415`; others are compared as is. Email addresses never match.
+pub fn in_area_codes(number: &str, area_codes: &[String]) -> bool {
+    match national_number(number) {
+        Some(national) => area_codes.iter().any(|code| national.starts_with(code)),
+        None => false,
+    }
+}
+
+fn national_number(number: &str) -> Option<String> {
+    if is_email(number) {
+        return None;
+    }
+
+    if number.starts_with('+') {
+        return phonenumber::parse(None, number)
+            .ok()
+            .map(|parsed| parsed.national().to_string());
+    }
+
+    Some(number.to_string())
+}
+
+/// Reads raw (unnormalized) recipients as CSV.
+///
+/// Names are read from the first column if any placeholder is bound to the name column, with
+/// numbers in the column after. The column after the numbers is read as an optional attachment
+/// path unless it is bound to a placeholder, `options.message_column`, `options.service_column`,
+/// `options.delay_column`, or `options.segment_column`. Unless `options.has_header` is set, the
+/// first row is treated as a recipient like any other.
+pub fn read_recipients<R: Read + Send>(
+    source: R,
+    options: &CsvOptions,
+    placeholders: &[Placeholder],
+) -> Result<Vec<Recipient>> {
+    let (_, recipients) = read_source(source, options, placeholders)?;
+    recipients.collect()
+}
+
+// Reads raw recipients lazily as in `read_recipients`, along with the CSV header row (if any)
+fn read_source<'a, R: Read + Send + 'a>(
+    source: R,
+    options: &CsvOptions,
+    placeholders: &[Placeholder],
+) -> Result<(Option<StringRecord>, RawRecipients<'a>)> {
+    let source =
+        decode(source, options.encoding).map_err(AmsgError::io("Failed to read recipients"))?;
+    let (header, recipients): (_, RawRecipients) = match options.format {
+        RecipientFormat::Csv => {
+            let (header, recipients) = read_csv(source, options, placeholders)?;
+            (header, Box::new(recipients))
+        }
+        RecipientFormat::Json => (
+            None,
+            Box::new(
+                read_json(source, options, placeholders)?
+                    .into_iter()
+                    .map(Ok),
+            ),
+        ),
+        RecipientFormat::Vcard => (
+            None,
+            Box::new(
+                read_vcard(source, options, placeholders)?
+                    .into_iter()
+                    .map(Ok),
+            ),
+        ),
+    };
+
+    // Explicitly bound placeholders take precedence over the built-in first name
+    let recipients = recipients.map(|r| {
+        r.map(|mut r| {
+            if let Some(name) = &r.name {
+                r.fields
+                    .entry(FIRST_NAME_PLACEHOLDER.to_string())
+                    .or_insert_with(|| first_name(name).to_string());
+            }
+
+            r
+        })
+    });
+
+    Ok((header, Box::new(recipients)))
+}
+
+// Strips any byte order mark from UTF-8, or else transcodes other encodings to UTF-8 (in which
+// a UTF-8 byte order mark takes precedence)
+fn decode<'a, R: Read + Send + 'a>(
+    source: R,
+    encoding: &'static Encoding,
+) -> io::Result<Box<dyn Read + Send + 'a>> {
+    if encoding != UTF_8 {
+        return Ok(Box::new(Transcoder {
+            source,
+            decoder: encoding.new_decoder(),
+            decoded: Vec::new(),
+            position: 0,
+            finished: false,
+        }));
+    }
+
+    let mut source = BufReader::new(source);
+
+    if source.fill_buf()?.starts_with(UTF8_BOM) {
+        source.consume(UTF8_BOM.len());
+    }
+
+    Ok(Box::new(source))
+}
+
+// Decodes text in another encoding into UTF-8 as it is read
+struct Transcoder<R> {
+    source: R,
+    decoder: Decoder,
+    decoded: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> Read for Transcoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.decoded.len() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            let mut chunk = [0; 8192];
+            let len = self.source.read(&mut chunk)?;
+            let capacity = self
+                .decoder
+                .max_utf8_buffer_length(len)
+                .ok_or_else(|| io::Error::other("Decoded recipients are too large"))?;
+
+            // With room for the worst case, the decoder always consumes the whole chunk
+            self.decoded.resize(capacity, 0);
+            let (_, _, written, _) =
+                self.decoder
+                    .decode_to_utf8(&chunk[..len], &mut self.decoded, len == 0);
+            self.decoded.truncate(written);
+            self.position = 0;
+            self.finished = len == 0;
+        }
+
+        let len = buf.len().min(self.decoded.len() - self.position);
+        buf[..len].copy_from_slice(&self.decoded[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+// Trims a name, tidying it up with `normalize_name` too if `normalize`
+fn clean_name(name: &str, normalize: bool) -> String {
+    if normalize {
+        normalize_name(name)
+    } else {
+        name.trim().to_string()
+    }
+}
+
+/// Tidies up a name for use in messages, collapsing runs of whitespace and title-casing each
+/// word (e.g., `JOHN   SMITH` becomes `John Smith`).
+///
+/// Letters after an apostrophe or hyphen, or after `Mc`, are capitalized too (e.g., `O'Brien`,
+/// `Smith-Jones`, and `McDonald`), while words already in mixed case are left as they are, since
+/// they are more likely deliberate (e.g., `MacKenzie` or `DeShawn`) than not.
+pub fn normalize_name(name: &str) -> String {
+    name.split_whitespace()
+        .map(|word| {
+            let mixed_case =
+                word.chars().any(char::is_uppercase) && word.chars().any(char::is_lowercase);
+
+            if mixed_case {
+                return word.to_string();
+            }
+
+            let mut normalized = String::with_capacity(word.len());
+            let mut capitalize = true;
+
+            for c in word.chars() {
+                if capitalize {
+                    normalized.extend(c.to_uppercase());
+                } else {
+                    normalized.extend(c.to_lowercase());
+                }
+
+                capitalize = matches!(c, '\'' | '’' | '-') || normalized == "Mc";
+            }
+
+            normalized
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The first word of a name, or the whole name if it is a single word
+fn first_name(name: &str) -> &str {
+    name.split_whitespace().next().unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+struct JsonRecipient {
+    number: Value,
+    name: Option<String>,
+    attachment: Option<String>,
+    #[serde(flatten)]
+    fields: Map<String, Value>,
+}
+
+// Reads raw recipients from a JSON array, with placeholders bound to fields by header name
+fn read_json<R: Read>(
+    source: R,
+    options: &CsvOptions,
+    placeholders: &[Placeholder],
+) -> Result<Vec<Recipient>> {
+    let entries: Vec<JsonRecipient> = serde_json::from_reader(source)
+        .map_err(AmsgError::json("Failed to parse JSON recipients"))?;
+
+    let name = |entry: &JsonRecipient| {
+        entry
+            .name
+            .as_deref()
+            .map(|name| clean_name(name, options.normalize_names))
+    };
+    let field = |entry: &JsonRecipient, column: &Column| match column {
+        Column::Name => Ok(name(entry).unwrap_or_default()),
+        Column::Header(key) => Ok(entry
+            .fields
+            .get(key)
+            .map(json_to_string)
+            .unwrap_or_default()),
+        Column::Index(index) => Err(AmsgError::InvalidRecipients(format!(
+            "Column {} cannot be referred to by index in JSON recipients",
+            index + 1
+        ))),
+    };
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let mut fields = HashMap::new();
+
+            for placeholder in placeholders {
+                fields.insert(
+                    placeholder.token.clone(),
+                    field(entry, &placeholder.column).map_err(|e| {
+                        AmsgError::InvalidRecipients(format!(
+                            "Failed to get value for {} from JSON recipient: {}",
+                            placeholder.token, e
+                        ))
+                    })?,
+                );
+            }
+
+            let read = |column: &Column| field(entry, column);
+            let source = "JSON recipient";
+
+            let message =
+                optional_field(options.message_column.as_ref(), read, "message", &source)?;
+            let service =
+                optional_field(options.service_column.as_ref(), read, "service", &source)?;
+            let delay = optional_field(options.delay_column.as_ref(), read, "delay", &source)?
+                .map(|delay| parse_recipient_delay(&delay, i as u64 + 1))
+                .transpose()?
+                .flatten();
+            let segment =
+                optional_field(options.segment_column.as_ref(), read, "segment", &source)?;
+
+            Ok(Recipient {
+                name: name(entry),
+                number: json_to_string(&entry.number).trim().to_string(),
+                attachment: entry
+                    .attachment
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|attachment| !attachment.is_empty())
+                    .map(String::from),
+                line: i as u64 + 1,
+                fields,
+                message,
+                service,
+                delay,
+                segment,
+                template: None,
+                record: StringRecord::new(),
+            })
+        })
+        .collect()
+}
+
+// The properties of a single vCard, by upper-cased name (without any group or parameters)
+struct Vcard {
+    // The line of the file on which the card begins
+    line: u64,
+    properties: Vec<(String, String)>,
+}
+
+impl Vcard {
+    fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.properties
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The formatted name, or else the given and family names from the structured name
+    fn name(&self) -> Option<String> {
+        if let Some(name) = self.value("FN").filter(|name| !name.trim().is_empty()) {
+            return Some(name.to_string());
+        }
+
+        let mut parts = self.value("N")?.split(';');
+        let family = parts.next().unwrap_or_default();
+        let given = parts.next().unwrap_or_default();
+        let name = format!("{} {}", given, family);
+
+        Some(name).filter(|name| !name.trim().is_empty())
+    }
+}
+
+// Reads raw recipients from vCards, one for the first (or, with `options.all_numbers`, each)
+// phone number on every card, with placeholders bound to other properties by name
+fn read_vcard<R: Read>(
+    mut source: R,
+    options: &CsvOptions,
+    placeholders: &[Placeholder],
+) -> Result<Vec<Recipient>> {
+    let mut text = String::new();
+    source
+        .read_to_string(&mut text)
+        .map_err(AmsgError::io("Failed to read vCard recipients"))?;
+
+    let mut recipients = Vec::new();
+    let mut without_numbers = 0;
+
+    for card in parse_vcards(&text) {
+        let name = card
+            .name()
+            .map(|name| clean_name(&name, options.normalize_names));
+        let field = |column: &Column| match column {
+            Column::Name => Ok(name.clone().unwrap_or_default()),
+            Column::Header(key) => Ok(card.value(key).unwrap_or_default().trim().to_string()),
+            Column::Index(index) => Err(AmsgError::InvalidRecipients(format!(
+                "Column {} cannot be referred to by index in vCard recipients",
+                index + 1
+            ))),
+        };
+        let source = format!("vCard on line {}", card.line);
+
+        let mut fields = HashMap::new();
+
+        for placeholder in placeholders {
+            fields.insert(
+                placeholder.token.clone(),
+                field(&placeholder.column).map_err(|e| {
+                    AmsgError::InvalidRecipients(format!(
+                        "Failed to get value for {} from vCard on line {}: {}",
+                        placeholder.token, card.line, e
+                    ))
+                })?,
+            );
+        }
+
+        let message = optional_field(options.message_column.as_ref(), field, "message", &source)?;
+        let service = optional_field(options.service_column.as_ref(), field, "service", &source)?;
+        let delay = optional_field(options.delay_column.as_ref(), field, "delay", &source)?
+            .map(|delay| parse_recipient_delay(&delay, card.line))
+            .transpose()?
+            .flatten();
+        let segment = optional_field(options.segment_column.as_ref(), field, "segment", &source)?;
+
+        // Version 4 cards may give numbers as `tel:` URIs
+        let numbers = card
+            .values("TEL")
+            .map(|number| {
+                let number = number.trim();
+                number
+                    .get(..4)
+                    .filter(|scheme| scheme.eq_ignore_ascii_case("tel:"))
+                    .map_or(number, |_| &number[4..])
+            })
+            .filter(|number| !number.is_empty())
+            .take(if options.all_numbers { usize::MAX } else { 1 })
+            .collect::<Vec<_>>();
+
+        if numbers.is_empty() {
+            without_numbers += 1;
+        }
+
+        for number in numbers {
+            recipients.push(Recipient {
+                name: name.clone(),
+                number: number.to_string(),
+                attachment: None,
+                line: card.line,
+                fields: fields.clone(),
+                message: message.clone(),
+                service: service.clone(),
+                delay,
+                segment: segment.clone(),
+                template: None,
+                record: StringRecord::new(),
+            });
+        }
+    }
+
+    if without_numbers > 0 {
+        debug!(
+            cards = without_numbers,
+            "Skipped vCards without a phone number"
+        );
+    }
+
+    Ok(recipients)
+}
+
+// Splits vCards into their properties, unfolding lines continued with leading whitespace and
+// unescaping values
+fn parse_vcards(text: &str) -> Vec<Vcard> {
+    let mut lines: Vec<(u64, String)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continued), Some((_, last))) => last.push_str(continued),
+            _ => lines.push((i as u64 + 1, line.to_string())),
+        }
+    }
+
+    let mut cards = Vec::new();
+    let mut card: Option<Vcard> = None;
+
+    for (line, content) in lines {
+        let Some((key, value)) = content.split_once(':') else {
+            continue;
+        };
+
+        // Properties may be grouped (e.g., `item1.TEL`) and carry parameters (e.g., `TEL;TYPE=CELL`)
+        let name = key.split(';').next().unwrap_or_default();
+        let name = name
+            .rsplit('.')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+
+        match (name.as_str(), value.trim().to_ascii_uppercase().as_str()) {
+            ("BEGIN", "VCARD") => {
+                card = Some(Vcard {
+                    line,
+                    properties: Vec::new(),
+                })
+            }
+            ("END", "VCARD") => cards.extend(card.take()),
+            _ => {
+                if let Some(card) = &mut card {
+                    card.properties.push((name, unescape_vcard_value(value)));
+                }
+            }
+        }
+    }
+
+    cards
+}
+
+fn unescape_vcard_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n' | 'N')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some(escaped @ (',' | ';' | ':' | '\\'))) => {
+                unescaped.push(escaped);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+// Strings are taken as-is, while other values (e.g., numbers) use their JSON representation
+fn json_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+fn read_csv<R: Read>(
+    source: R,
+    options: &CsvOptions,
+    placeholders: &[Placeholder],
+) -> Result<(Option<StringRecord>, CsvRecipients<R>)> {
+    // Comment lines need not have as many fields as records
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(options.has_header)
+        .delimiter(options.delimiter)
+        .flexible(true)
+        .from_reader(source);
+
+    let headers = if options.has_header {
+        Some(
+            rdr.headers()
+                .map_err(AmsgError::csv("Failed to read CSV header"))?
+                .clone(),
+        )
+    } else {
+        None
+    };
+
+    let columns = placeholders
+        .iter()
+        .map(|p| {
+            resolve_column(&p.column, headers.as_ref())
+                .map(|column| (p.token.clone(), column))
+                .map_err(|e| {
+                    AmsgError::InvalidRecipients(format!(
+                        "Failed to resolve column of placeholder {}: {}",
+                        p.token, e
+                    ))
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let resolve_optional = |column: &Option<Column>, what: &str| {
+        column
+            .as_ref()
+            .map(|c| resolve_column(c, headers.as_ref()))
+            .transpose()
+            .map_err(|e| {
+                AmsgError::InvalidRecipients(format!("Failed to resolve {} column: {}", what, e))
+            })
+            .map(Option::flatten)
+    };
+
+    let message_column = resolve_optional(&options.message_column, "message")?;
+    let service_column = resolve_optional(&options.service_column, "service")?;
+    let delay_column = resolve_optional(&options.delay_column, "delay")?;
+    let segment_column = resolve_optional(&options.segment_column, "segment")?;
+
+    let has_names = columns.iter().any(|(_, column)| column.is_none());
+    let attachment_column = if has_names { 2 } else { 1 };
+    let has_attachments = columns
+        .iter()
+        .map(|(_, column)| column)
+        .chain([
+            &message_column,
+            &service_column,
+            &delay_column,
+            &segment_column,
+        ])
+        .all(|&c| c != Some(attachment_column));
+
+    let layout = CsvLayout {
+        columns,
+        message_column,
+        service_column,
+        delay_column,
+        segment_column,
+        has_names,
+        attachment_column: Some(attachment_column).filter(|_| has_attachments),
+        normalize_names: options.normalize_names,
+    };
+
+    Ok((
+        headers,
+        CsvRecipients {
+            records: rdr.into_records(),
+            layout,
+            comments: 0,
+        },
+    ))
+}
+
+// Recipients converted from CSV records as the records are read
+struct CsvRecipients<R> {
+    records: StringRecordsIntoIter<R>,
+    layout: CsvLayout,
+    comments: usize,
+}
+
+impl<R: Read> Iterator for CsvRecipients<R> {
+    type Item = Result<Recipient>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for result in self.records.by_ref() {
+            let record = match result.map_err(AmsgError::csv("Failed to read CSV record")) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+
+            // Blank lines are dropped by the reader itself, but not records of blank cells
+            if record.iter().all(|field| field.trim().is_empty())
+                || record
+                    .get(0)
+                    .is_some_and(|field| field.trim_start().starts_with('#'))
+            {
+                self.comments += 1;
+                continue;
+            }
+
+            return Some(self.layout.recipient(record));
+        }
+
+        if self.comments > 0 {
+            debug!("Skipped {} blank or comment line(s) in CSV", self.comments);
+            self.comments = 0;
+        }
+
+        None
+    }
+}
+
+// Where each part of a recipient is found in a CSV record
+struct CsvLayout {
+    // The token of each placeholder and its column, or `None` for the name column
+    columns: Vec<(String, Option<usize>)>,
+    message_column: Option<usize>,
+    service_column: Option<usize>,
+    delay_column: Option<usize>,
+    segment_column: Option<usize>,
+    has_names: bool,
+    attachment_column: Option<usize>,
+    normalize_names: bool,
+}
+
+impl CsvLayout {
+    fn recipient(&self, record: StringRecord) -> Result<Recipient> {
+        let line = record.position().map_or(0, |p| p.line());
+        let missing = |what: &str| AmsgError::InvalidRecord {
+            line,
+            reason: format!("Failed to get {} from CSV record", what),
+        };
+
+        let (name, number) = if self.has_names {
+            (
+                Some(clean_name(
+                    record.get(0).ok_or_else(|| missing("name"))?,
+                    self.normalize_names,
+                )),
+                record
+                    .get(1)
+                    .ok_or_else(|| missing("number"))?
+                    .trim()
+                    .to_string(),
+            )
+        } else {
+            (
+                None,
+                record
+                    .get(0)
+                    .ok_or_else(|| missing("number"))?
+                    .trim()
+                    .to_string(),
+            )
+        };
+
+        // Short rows leave the columns they lack blank
+        let field = |column: usize| Ok(record.get(column).unwrap_or_default().to_string());
+        let source = "CSV record";
+
+        let attachment = optional_field(self.attachment_column, field, "attachment", &source)?;
+        let message = optional_field(self.message_column, field, "message", &source)?;
+        let service = optional_field(self.service_column, field, "service", &source)?;
+        let delay = optional_field(self.delay_column, field, "delay", &source)?
+            .map(|delay| parse_recipient_delay(&delay, line))
+            .transpose()?
+            .flatten();
+        let segment = optional_field(self.segment_column, field, "segment", &source)?;
+
+        let mut fields = HashMap::new();
+
+        for (token, column) in &self.columns {
+            let value = match column {
+                Some(column) => record
+                    .get(*column)
+                    .ok_or_else(|| missing(&format!("value for {}", token)))?
+                    .trim()
+                    .to_string(),
+                None => name.clone().unwrap_or_default(),
+            };
+
+            fields.insert(token.clone(), value);
+        }
+
+        Ok(Recipient {
+            name,
+            number,
+            attachment,
+            line,
+            fields,
+            message,
+            service,
+            delay,
+            segment,
+            template: None,
+            record,
+        })
+    }
+}
+
+// Reads an optional field (e.g., a recipient's own message) through `field`, treating a blank
+// value as absent and naming `source` (where the recipient was read from) if it cannot be read
+fn optional_field<C>(
+    column: Option<C>,
+    field: impl FnOnce(C) -> Result<String>,
+    what: &str,
+    source: &dyn fmt::Display,
+) -> Result<Option<String>> {
+    let value = column.map(field).transpose().map_err(|e| {
+        AmsgError::InvalidRecipients(format!("Failed to get {} from {}: {}", what, source, e))
+    })?;
+
+    Ok(value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty()))
+}
+
+// Blank cells fall back to the default delay, but malformed ones are taken as a mistake in the
+// file rather than in the recipient, so they are not just skipped
+fn parse_recipient_delay(delay: &str, line: u64) -> Result<Option<Duration>> {
+    let delay = delay.trim();
+
+    if delay.is_empty() {
+        return Ok(None);
+    }
+
+    parse_duration(delay)
+        .map(Some)
+        .map_err(|e| AmsgError::InvalidRecord {
+            line,
+            reason: format!("Invalid delay: {}", e),
+        })
+}
+
+// Resolves a column to an index, or `None` for the name column
+fn resolve_column(column: &Column, headers: Option<&StringRecord>) -> Result<Option<usize>> {
+    match column {
+        Column::Name => Ok(None),
+        Column::Index(index) => Ok(Some(*index)),
+        Column::Header(header) => {
+            let Some(headers) = headers else {
+                return Err(AmsgError::InvalidRecipients(format!(
+                    "Column {} is referred to by name, but the CSV has no header",
+                    header
+                )));
+            };
+
+            headers
+                .iter()
+                .position(|h| h.trim() == header)
+                .map(Some)
+                .ok_or_else(|| {
+                    AmsgError::InvalidRecipients(format!(
+                        "Column {} not found in CSV header",
+                        header
+                    ))
+                })
+        }
+    }
+}
+
+// Whether numbers are masked wherever they are displayed via `DisplayNumber`
+static MASK_NUMBERS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether numbers in logs (and errors) are masked with [`mask_number`].
+pub fn set_number_masking(enabled: bool) {
+    MASK_NUMBERS.store(enabled, Ordering::Relaxed);
+}
+
+/// Displays a number (or Apple ID email) for logs, masked if enabled by
+/// [`set_number_masking`].
+pub struct DisplayNumber<'a>(pub &'a str);
+
+impl fmt::Display for DisplayNumber<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if MASK_NUMBERS.load(Ordering::Relaxed) {
+            f.write_str(&mask_number(self.0))
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
+
+/// Redacts all but the last four digits of a number (e.g., `+*******8910`), or all but the
+/// first character of an email's local part (e.g., `b**@example.com`).
+pub fn mask_number(number: &str) -> String {
+    if let Some((local, domain)) = number.split_once('@') {
+        let mut chars = local.chars();
+        let first = chars.next().map(String::from).unwrap_or_default();
+        return format!("{}{}@{}", first, "*".repeat(chars.count()), domain);
+    }
+
+    let mut kept = number
+        .chars()
+        .filter(char::is_ascii_digit)
+        .count()
+        .saturating_sub(4);
+
+    number
+        .chars()
+        .map(|c| {
+            if c.is_ascii_digit() && kept > 0 {
+                kept -= 1;
+                '*'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Checks whether `address` looks like an email address (e.g., an Apple ID).
+pub fn is_email(address: &str) -> bool {
+    let Some((local, domain)) = address.trim().split_once('@') else {
+        return false;
+    };
+
+    let valid_local = !local.is_empty()
+        && !local
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '@');
+
+    let labels = domain.split('.').collect::<Vec<_>>();
+    let valid_domain = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()));
+
+    valid_local && valid_domain
+}
+
+/// Validates a phone number and normalizes it to its digits, keeping any leading `+`.
+///
+/// If `options.country` is provided, the number is further validated and normalized to E.164,
+/// with numbers lacking a leading `+` interpreted as national numbers of that country.
+pub fn process_number(number: &str, options: &NumberOptions) -> Result<String> {
+    let number = number.trim();
+    let invalid = |reason: String| AmsgError::InvalidNumber {
+        number: number.to_string(),
+        reason,
+    };
+
+    // Errors still quote the number as given, letters and all
+    let converted = options.vanity.then(|| vanity_to_digits(number));
+    let dialed = converted.as_deref().unwrap_or(number);
+
+    let (has_plus, stem) = if let Some(stripped) = dialed.strip_prefix('+') {
+        (true, stripped)
+    } else {
+        (false, dialed)
+    };
+
+    if !stem
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ' ' || c == '-' || c == '(' || c == ')')
+    {
+        return Err(invalid(String::from("contains invalid characters")));
+    }
+
+    let mut digits = stem
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>();
+
+    // Much of the world dials `00` for international calls, which only means the same as a
+    // leading `+` once numbers are known to be parsed as international or national
+    let has_plus = if !has_plus && options.country.is_some() && digits.starts_with("00") {
+        digits.drain(..2);
+        true
+    } else {
+        has_plus
+    };
+
+    let len = digits.len();
+
+    if len < options.min_digits {
+        return Err(invalid(String::from("is too short")));
+    } else if len > options.max_digits {
+        return Err(invalid(String::from("is too long")));
+    }
+
+    let Some(country) = options.country else {
+        return Ok(if has_plus {
+            format!("+{}", digits)
+        } else {
+            digits
+        });
+    };
+
+    let parsed = if has_plus {
+        phonenumber::parse(None, format!("+{}", digits))
+    } else {
+        phonenumber::parse(Some(country), &digits)
+    }
+    .map_err(|e| invalid(format!("could not be parsed: {}", e)))?;
+
+    if !parsed.is_valid() {
+        return Err(invalid(String::from("is not valid")));
+    }
+
+    Ok(parsed.format().mode(Mode::E164).to_string())
+}
+
+/// Whether a normalized (E.164) number is classified as a fixed line, which cannot receive SMS.
+///
+/// Numbers that may be either (e.g., in the US, where landlines and mobiles share the same
+/// ranges) are not taken to be landlines.
+pub fn is_landline(number: &str) -> bool {
+    phonenumber::parse(None, number)
+        .is_ok_and(|parsed| parsed.number_type(&DATABASE) == Type::FixedLine)
+}
+
+/// Converts the letters in a vanity number (e.g., `1-800-FLOWERS`) to the digits on the same
+/// phone keypad keys, ignoring case and leaving everything else as is.
+pub fn vanity_to_digits(number: &str) -> String {
+    number
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            'A'..='C' => '2',
+            'D'..='F' => '3',
+            'G'..='I' => '4',
+            'J'..='L' => '5',
+            'M'..='O' => '6',
+            'P'..='S' => '7',
+            'T'..='V' => '8',
+            'W'..='Z' => '9',
+            _ => c,
+        })
+        .collect()
+}
+
+/// Expands spintax (e.g., `{Hi|Hello|Hey} there`) in `template` by picking one option of each
+/// group at random.
+///
+/// Only braces containing a top-level `|` are treated as spintax, so placeholder tokens like
+/// `{name}` are left intact (even within options). Groups may be nested, and unmatched braces
+/// are kept as is.
+pub fn spin_message<R: Rng + ?Sized>(template: &str, rng: &mut R) -> String {
+    spin_with(template, &mut |options| rng.random_range(..options.len()))
+}
+
+/// Expands spintax like [`spin_message`], but picks the same options every time for the same
+/// `number` and `seed` (e.g., so that a preview shows the very message later sent).
+pub fn spin_message_for(template: &str, number: &str, seed: u64) -> String {
+    spin_message(
+        template,
+        &mut StdRng::seed_from_u64(stable_hash(number, seed)),
+    )
+}
+
+/// Whether `template` may render (with `fields` substituted) to nothing but whitespace,
+/// whichever options of its spintax [`spin_message`] picks.
+pub fn may_render_blank(template: &str, fields: &HashMap<String, String>) -> bool {
+    render_message(&spin_blankest(template, fields), fields)
+        .trim()
+        .is_empty()
+}
+
+// Expands spintax like `spin_message`, but picking an option that renders blank wherever one does
+fn spin_blankest(template: &str, fields: &HashMap<String, String>) -> String {
+    spin_with(template, &mut |options| {
+        options
+            .iter()
+            .position(|option| may_render_blank(option, fields))
+            .unwrap_or_default()
+    })
+}
+
+// Expands spintax, picking the option of each group (by index) with `choose`
+fn spin_with(template: &str, choose: &mut dyn FnMut(&[&str]) -> usize) -> String {
+    let mut spun = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        spun.push_str(&rest[..open]);
+
+        let Some(close) = matching_brace(&rest[open..]).map(|close| open + close) else {
+            spun.push('{');
+            rest = &rest[open + 1..];
+            continue;
+        };
+
+        let inner = &rest[open + 1..close];
+        let options = split_spintax_options(inner);
+
+        if options.len() > 1 {
+            let option = options[choose(&options)];
+            spun.push_str(&spin_with(option, choose));
+        } else {
+            spun.push('{');
+            spun.push_str(&spin_with(inner, choose));
+            spun.push('}');
+        }
+
+        rest = &rest[close + 1..];
+    }
+
+    spun.push_str(rest);
+    spun
+}
+
+// The index of the `}` closing the `{` that `text` starts with, if any
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+// Splits the contents of a brace group at each `|` not nested within further braces
+fn split_spintax_options(inner: &str) -> Vec<&str> {
+    let mut options = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    for (i, c) in inner.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '|' if depth == 0 => {
+                options.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    options.push(&inner[start..]);
+    options
+}
+
+/// Finds the distinct `{...}` tokens in `template` that may be meant as placeholders (i.e.,
+/// non-empty and containing neither braces nor `|`, unlike spintax groups).
+pub fn find_brace_tokens(template: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        rest = &rest[open..];
+
+        let Some(len) = rest[1..].find(['{', '}', '|']).map(|i| i + 1) else {
+            break;
+        };
+
+        let token = &rest[..=len];
+
+        if rest[len..].starts_with('}') && len > 1 && !tokens.contains(&token) {
+            tokens.push(token);
+        }
+
+        rest = &rest[len..];
+    }
+
+    tokens
+}
+
+/// Appends `footer` (e.g., opt-out instructions) on a new line after `message`, unless the
+/// message already ends with it.
+pub fn append_footer(message: String, footer: &str) -> String {
+    let footer = footer.trim();
+
+    if footer.is_empty() || message.trim_end().ends_with(footer) {
+        return message;
+    }
+
+    format!("{}\n{}", message.trim_end(), footer)
+}
+
+/// Substitutes each placeholder token in `template` with its value from `fields`.
+///
+/// Substitution happens in a single pass (preferring the longest token at each position), so
+/// tokens appearing within substituted values are left intact.
+pub fn render_message(template: &str, fields: &HashMap<String, String>) -> String {
+    let mut message = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(c) = rest.chars().next() {
+        let matched = fields
+            .iter()
+            .filter(|(token, _)| !token.is_empty() && rest.starts_with(token.as_str()))
+            .max_by_key(|(token, _)| token.len());
+
+        if let Some((token, value)) = matched {
+            message.push_str(value);
+            rest = &rest[token.len()..];
+        } else {
+            message.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    message
+}
+
+/// Substitutes each `${NAME}` in `template` with the value `lookup` gives for `NAME`,
+/// returning the result along with the names `lookup` had no value for (which are left as is).
+///
+/// Only names made up of ASCII letters, digits, and underscores (not starting with a digit) are
+/// substituted, so other text with a `$` before braces is left alone.
+pub fn expand_env_vars<F: Fn(&str) -> Option<String>>(
+    template: &str,
+    lookup: F,
+) -> (String, Vec<String>) {
+    let mut expanded = String::with_capacity(template.len());
+    let mut unset = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let name = rest[2..]
+            .split_once('}')
+            .map(|(name, _)| name)
+            .filter(|name| {
+                name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+
+        let Some(name) = name else {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+
+        let variable = &rest[..name.len() + 3];
+
+        match lookup(name) {
+            Some(value) => expanded.push_str(&value),
+            None => {
+                expanded.push_str(variable);
+
+                if !unset.iter().any(|unset| unset == name) {
+                    unset.push(name.to_string());
+                }
+            }
+        }
+
+        rest = &rest[variable.len()..];
+    }
+
+    expanded.push_str(rest);
+    (expanded, unset)
+}
+
+/// Estimates the number of SMS segments needed to send `message`.
+///
+/// Messages using only the GSM-7 alphabet fit 160 characters in a single segment (153 per
+/// segment once split), while any other character forces UCS-2 encoding with 70 (or 67)
+/// characters per segment.
+pub fn sms_segments(message: &str) -> usize {
+    let septets = message
+        .chars()
+        .map(|c| {
+            if GSM7_BASIC.contains(c) {
+                Some(1)
+            } else if GSM7_EXTENSION.contains(c) {
+                Some(2)
+            } else {
+                None
+            }
+        })
+        .sum::<Option<usize>>();
+
+    let (length, single, multi) = match septets {
+        Some(septets) => (septets, 160, 153),
+        None => (message.encode_utf16().count(), 70, 67),
+    };
+
+    if length <= single {
+        1
+    } else {
+        length.div_ceil(multi)
+    }
+}
+
+/// Checks that a recipient's attachment exists, resolving it to the absolute path Messages
+/// expects.
+///
+/// A recipient without an attachment of their own is given `default` (which must already be
+/// resolved), if provided. Their own attachment is resolved as in [`resolve_attachment_in`]
+/// against `base` (e.g., the directory of the recipients file).
+pub fn resolve_recipient_attachment(
+    recipient: &mut Recipient,
+    default: Option<&str>,
+    base: Option<&Path>,
+) -> Result<()> {
+    recipient.attachment = match &recipient.attachment {
+        Some(path) => Some(resolve_attachment_in(path, base)?),
+        None => default.map(String::from),
+    };
+
+    Ok(())
+}
+
+/// Checks that an attachment exists, resolving it to the absolute path Messages expects.
+///
+/// A leading `~` stands for the home directory, as in a shell.
+pub fn resolve_attachment(path: &str) -> Result<String> {
+    resolve_attachment_in(path, None)
+}
+
+/// Resolves an attachment as in [`resolve_attachment`], but with relative paths taken relative
+/// to `base` (if given) rather than the current directory.
+pub fn resolve_attachment_in(path: &str, base: Option<&Path>) -> Result<String> {
+    let located = match (expand_home(path), base) {
+        (located, Some(base)) if located.is_relative() => base.join(located),
+        (located, _) => located,
+    };
+
+    if !located.exists() {
+        return Err(AmsgError::FileNotFound(located.display().to_string()));
+    }
+
+    if !located.is_file() {
+        return Err(AmsgError::InvalidPath(format!(
+            "Attachment {} is not a file",
+            located.display()
+        )));
+    }
+
+    let resolved = fs::canonicalize(&located).map_err(AmsgError::io(format!(
+        "Failed to resolve attachment {}",
+        located.display()
+    )))?;
+
+    Ok(resolved.to_string_lossy().into_owned())
+}
+
+// Expands a leading `~` (alone or before a `/`) to the home directory, as a shell would
+fn expand_home(path: &str) -> PathBuf {
+    let home = env::var_os("HOME").map(PathBuf::from);
+
+    match (path.strip_prefix('~'), home) {
+        (Some(""), Some(home)) => home,
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Reads the message template from a text file.
+pub fn read_message(path: &str) -> Result<String> {
+    fs::read_to_string(path).map_err(AmsgError::io(format!(
+        "Failed to read message from {}",
+        path
+    )))
+}
+
+/// Sends a message with `send` (given the service to use), retrying failed `osascript` runs
+/// with exponential backoff, and returns the service that delivered it.
+///
+/// If the recipient is not registered with `service`, the message is instead sent via
+/// `fallback_service` (if any).
+pub fn send_with_retries<'a, F: FnMut(&str) -> Result<()>>(
+    number: &str,
+    service: &'a str,
+    fallback_service: Option<&'a str>,
+    max_retries: u32,
+    base_delay: Duration,
+    mut send: F,
+) -> Result<&'a str> {
+    let mut send = |service| retry(number, max_retries, base_delay, || send(service));
+
+    match (send(service), fallback_service) {
+        (Err(e), Some(fallback_service)) if e.is_not_registered() => {
+            warn!(
+                number = %DisplayNumber(number),
+                service = %service,
+                fallback_service = %fallback_service,
+                error = %e,
+                "Recipient not registered with service, falling back"
+            );
+            send(fallback_service).map(|()| fallback_service)
+        }
+        (result, _) => result.map(|()| service),
+    }
+}
+
+/// Calls `send` until it succeeds, retrying failed `osascript` runs with exponential backoff.
+///
+/// `target` identifies the recipient (or group) in logs.
+pub fn retry<F: FnMut() -> Result<()>>(
+    target: &str,
+    max_retries: u32,
+    base_delay: Duration,
+    mut send: F,
+) -> Result<()> {
+    let mut attempt = 0;
+    let mut wait = base_delay;
+
+    loop {
+        match send() {
+            // Only non-zero `osascript` exits are considered transient (e.g., throttling), and
+            // unregistered recipients will not become registered by retrying
+            Err(e)
+                if attempt < max_retries
+                    && matches!(e, AmsgError::AppleScriptFailed(_))
+                    && !e.is_not_registered() =>
+            {
+                attempt += 1;
+                warn!(
+                    target = %DisplayNumber(target),
+                    attempt,
+                    max_retries,
+                    wait = %humantime::format_duration(wait),
+                    error = %e,
+                    "Retrying failed send"
+                );
+                thread::sleep(wait);
+                wait = wait.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How often (in recipients completed) [`send_batch`] logs its progress and estimated time left.
+const ETA_INTERVAL: usize = 10;
+
+/// The files (if any) to which the outcome for each recipient is written, besides the log.
+#[derive(Default)]
+pub struct Records {
+    pub report: Option<Report>,
+    pub checkpoint: Option<Checkpoint>,
+    #[cfg(feature = "history")]
+    pub history: Option<History>,
+    pub failures: Option<FailureLog>,
+    pub events: Option<EventStream<io::Stdout>>,
+    pub dry_run_out: Option<DryRunOutput>,
+}
+
+impl Records {
+    /// Records the recipients excluded before sending in the report and event stream (if any).
+    pub fn record_skipped(&mut self, skipped: &[SkippedRecipient]) -> Result<()> {
+        if let Some(report) = &mut self.report {
+            for s in skipped {
+                report.record(&s.recipient, Status::Skipped, Some(&s.reason))?;
+            }
+        }
+
+        if let Some(events) = &mut self.events {
+            for s in skipped {
+                events.record(&s.recipient, Status::Skipped, Some(&s.reason))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The number of messages sent (or, in a dry run, rendered) and failed by [`send_batch`].
+#[derive(Default)]
+pub struct Tally {
+    pub sent: usize,
+    pub failed: usize,
+    /// How long each recipient's sends took, excluding the waits between retries
+    pub latencies: Vec<Duration>,
+    #[cfg(feature = "webhook")]
+    pub failures: Vec<FailedSend>,
+}
+
+/// How [`send_batch`] sends to each recipient.
+pub struct BatchOptions<'a> {
+    /// The service for recipients that do not name their own
+    pub service: &'a str,
+    /// The service to fall back on for recipients not registered with theirs
+    pub fallback_service: Option<&'a str>,
+    pub max_retries: u32,
+    pub retry_base_delay: Duration,
+    /// How many sends may be in flight at once
+    pub concurrency: u32,
+    pub limiter: RateLimiter,
+    /// Logs (and writes to [`Records::dry_run_out`]) each message instead of sending it
+    pub dry_run: bool,
+    /// Renders the message for a recipient
+    pub render: &'a (dyn Fn(&Recipient) -> String + Sync),
+    /// Once set (e.g., on Ctrl-C), no new sends are started
+    pub interrupted: &'a AtomicBool,
+    /// Called as each recipient is sent to (or fails)
+    pub progress: &'a (dyn Fn() + Sync),
+}
+
+/// Sends to `count` recipients, taking each from `recipients` only once a worker is free for
+/// it, and records each outcome in `records`.
+///
+/// Once reading a recipient fails, the rest are abandoned and the error is returned after the
+/// sends in flight finish.
+pub fn send_batch<I: Iterator<Item = Result<Recipient>> + Send>(
+    sender: &dyn MessageSender,
+    recipients: I,
+    count: usize,
+    options: &BatchOptions,
+    records: &mut Records,
+) -> Result<Tally> {
+    if options.dry_run {
+        let mut sent = 0;
+
+        for recipient in recipients {
+            let recipient = recipient?;
+            let service = recipient.service.as_deref().unwrap_or(options.service);
+            let message = (options.render)(&recipient);
+
+            info!(
+                recipient = recipient.name.as_deref(),
+                number = %DisplayNumber(&recipient.number),
+                service,
+                attachment = recipient.attachment.as_deref(),
+                text = %message,
+                "[Dry run] Would send message"
+            );
+
+            if let Some(output) = records.dry_run_out.as_mut() {
+                output.record(&recipient, service, &message)?;
+            }
+
+            sent += 1;
+            (options.progress)();
+        }
+
+        return Ok(Tally {
+            sent,
+            ..Tally::default()
+        });
+    }
+
+    let recipients = Mutex::new(Some(recipients));
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut tally = Tally::default();
+    let mut read_error = None;
+    let interrupted = || options.interrupted.load(Ordering::SeqCst);
+
+    thread::scope(|scope| {
+        for _ in 0..options.concurrency {
+            let tx = tx.clone();
+            let recipients = &recipients;
+            let limiter = &options.limiter;
+
+            scope.spawn(move || {
+                while !interrupted()
+                    && let Some(next) = next_recipient(recipients)
+                {
+                    let recipient = match next {
+                        Ok(recipient) => recipient,
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                            break;
+                        }
+                    };
+                    let message = (options.render)(&recipient);
+
+                    limiter.acquire_with_delay(recipient.delay);
+
+                    // Waiting for the limiter may take a while, so check again before sending
+                    if interrupted() {
+                        break;
+                    }
+
+                    let mut latency = Duration::ZERO;
+                    let result = send_with_retries(
+                        &recipient.number,
+                        recipient.service.as_deref().unwrap_or(options.service),
+                        options.fallback_service,
+                        options.max_retries,
+                        options.retry_base_delay,
+                        |service| {
+                            let attempt = Instant::now();
+                            let result = sender.send(
+                                &message,
+                                &recipient.number,
+                                service,
+                                recipient.attachment.as_deref(),
+                            );
+                            latency += attempt.elapsed();
+                            result
+                        },
+                    )
+                    // The service may be the recipient's own, which is sent along with them
+                    .map(String::from);
+
+                    // Recipients not registered with the service say nothing about throttling
+                    limiter.adapt(result.as_ref().is_err_and(|e| !e.is_not_registered()));
+                    limiter.release_with_delay(recipient.delay);
+
+                    if tx.send(Ok((recipient, result, latency))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Only the workers' senders should keep the channel open
+        drop(tx);
+
+        // Outcomes are logged and recorded from this thread as each send completes
+        for outcome in rx {
+            let (recipient, result, latency) = match outcome {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    read_error = Some(e);
+                    continue;
+                }
+            };
+
+            tally.latencies.push(latency);
+
+            match result {
+                Ok(service) => {
+                    tally.sent += 1;
+                    info!(
+                        recipient = recipient.name.as_deref(),
+                        number = %DisplayNumber(&recipient.number),
+                        service = %service,
+                        status = Status::Sent.as_str(),
+                        "Message sent"
+                    );
+
+                    if let Some(report) = records.report.as_mut() {
+                        report.record_with_latency(
+                            &recipient,
+                            Status::Sent,
+                            None,
+                            Some(latency),
+                        )?;
+                    }
+
+                    if let Some(events) = records.events.as_mut() {
+                        events.record(&recipient, Status::Sent, None)?;
+                    }
+
+                    if let Some(checkpoint) = records.checkpoint.as_mut() {
+                        checkpoint.record(&recipient.number)?;
+                    }
+
+                    #[cfg(feature = "history")]
+                    if let Some(history) = records.history.as_mut() {
+                        history.record(&recipient.number)?;
+                    }
+                }
+                Err(e) => {
+                    tally.failed += 1;
+                    error!(
+                        recipient = recipient.name.as_deref(),
+                        number = %DisplayNumber(&recipient.number),
+                        status = Status::Failed.as_str(),
+                        error = %e,
+                        "Failed to send message"
+                    );
+
+                    if let Some(report) = records.report.as_mut() {
+                        report.record_with_latency(
+                            &recipient,
+                            Status::Failed,
+                            Some(&e.to_string()),
+                            Some(latency),
+                        )?;
+                    }
+
+                    if let Some(events) = records.events.as_mut() {
+                        events.record(&recipient, Status::Failed, Some(&e.to_string()))?;
+                    }
+
+                    #[cfg(feature = "webhook")]
+                    tally.failures.push(FailedSend::new(&recipient, &e));
+
+                    if let Some(failures) = records.failures.as_mut() {
+                        failures.record(&recipient)?;
+                    }
+                }
+            }
+
+            (options.progress)();
+
+            let done = tally.sent + tally.failed;
+
+            if done % ETA_INTERVAL == 0
+                && done < count
+                && let Some(remaining) = estimate_remaining(started.elapsed(), done, count)
+            {
+                // Whole seconds (rounded up, so as never to read "0s") are much easier to read
+                let eta = humantime::format_duration(Duration::from_secs(
+                    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0),
+                ));
+                info!(
+                    done,
+                    total = count,
+                    "Sent to {} of {} recipient(s), about {} remaining",
+                    done,
+                    count,
+                    eta
+                );
+            }
+        }
+
+        if let Some(e) = read_error {
+            return Err(e);
+        }
+
+        if interrupted() {
+            warn!(
+                "Interrupted with {} recipient(s) not yet sent to",
+                count - tally.sent - tally.failed
+            );
+        }
+
+        Ok(tally)
+    })
+}
+
+// Takes the next recipient for a worker, abandoning the rest if reading it fails
+fn next_recipient<I: Iterator<Item = Result<Recipient>>>(
+    recipients: &Mutex<Option<I>>,
+) -> Option<Result<Recipient>> {
+    let mut recipients = recipients.lock().unwrap_or_else(PoisonError::into_inner);
+
+    match recipients.as_mut()?.next() {
+        Some(Err(e)) => {
+            *recipients = None;
+            Some(Err(e))
+        }
+        next => next,
+    }
+}
+
+/// Sends a single message to `number` via the given Messages service, followed by an
+/// optional attachment (given as an absolute path).
+///
+/// `osascript` is killed if it has not finished within `timeout`.
+pub fn send_message(
+    message: &str,
+    number: &str,
+    service: &str,
+    attachment: Option<&str>,
+    timeout: Duration,
+) -> Result<()> {
+    validate_service(service)?;
+    run_apple_script(
+        buddy_script(message, number, service, attachment, activation()),
+        timeout,
+    )
+    .map(drop)
+}
+
+/// Sends a single message to an existing group chat, identified by its name or ID, followed
+/// by an optional attachment (given as an absolute path).
+///
+/// `osascript` is killed if it has not finished within `timeout`.
+pub fn send_group_message(
+    message: &str,
+    group: &str,
+    attachment: Option<&str>,
+    timeout: Duration,
+) -> Result<()> {
+    run_apple_script(
+        group_script(message, group, attachment, activation()),
+        timeout,
+    )
+    .map(drop)
+}
+
+/// The services (e.g., `iMessage` and `SMS`) of the accounts set up in Messages.
+///
+/// Messages is only asked on the first call, with the answer reused for the rest of the run.
+pub fn available_services(timeout: Duration) -> Result<&'static [String]> {
+    static SERVICES: OnceLock<Vec<String>> = OnceLock::new();
+
+    if let Some(services) = SERVICES.get() {
+        return Ok(services);
+    }
+
+    let mut services = Vec::<String>::new();
+
+    for service in run_apple_script(SERVICES_SCRIPT.to_string(), timeout)?.lines() {
+        let service = service.trim();
+
+        // Several accounts (e.g., two Apple IDs) may share a service
+        if !service.is_empty() && !services.iter().any(|s| s.eq_ignore_ascii_case(service)) {
+            services.push(service.to_string());
+        }
+    }
+
+    Ok(SERVICES.get_or_init(|| services))
+}
+
+/// Checks that `service` is one of the [`available_services`], ignoring case.
+pub fn check_service_available(service: &str, timeout: Duration) -> Result<()> {
+    let services = available_services(timeout)?;
+
+    if services.iter().any(|s| s.eq_ignore_ascii_case(service)) {
+        return Ok(());
+    }
+
+    Err(AmsgError::InvalidArgument(format!(
+        "Service {} is not set up in Messages (available: {})",
+        service,
+        if services.is_empty() {
+            String::from("none")
+        } else {
+            services.join(", ")
+        }
+    )))
+}
+
+/// Checks that `osascript` can run a trivial script, without involving Messages.
+pub fn check_apple_script(timeout: Duration) -> Result<()> {
+    run_apple_script(String::from("return 1"), timeout).map(drop)
+}
+
+/// Launches Messages if it is not already running, waiting until it can be scripted.
+///
+/// Sends otherwise race the launch, in which case the first few may fail.
+pub fn ensure_messages_running(timeout: Duration) -> Result<()> {
+    run_apple_script(LAUNCH_SCRIPT.to_string(), timeout).map(drop)
+}
+
+/// Quits Messages (e.g., once a batch completes on a dedicated Mac).
+pub fn quit_messages(timeout: Duration) -> Result<()> {
+    run_apple_script(
+        String::from(r#"tell application "Messages" to quit"#),
+        timeout,
+    )
+    .map(drop)
+}
+
+// Messages reports itself as running before it is ready, so wait until its accounts load
+const LAUNCH_SCRIPT: &str = r#"
+if application "Messages" is not running then
+    tell application "Messages" to launch
+end if
+repeat 100 times
+    try
+        tell application "Messages" to count of accounts
+        exit repeat
+    on error
+        delay 0.1
+    end try
+end repeat
+"#;
+
+// Lists the service of each account in Messages, one per line
+const SERVICES_SCRIPT: &str = r#"
+set serviceTypes to {}
+tell application "Messages"
+    repeat with currentAccount in accounts
+        set end of serviceTypes to (service type of currentAccount) as text
+    end repeat
+end tell
+set AppleScript's text item delimiters to linefeed
+serviceTypes as text
+"#;
+
+/// A long-lived `osascript` process that runs the AppleScript for each message sent through
+/// it, avoiding the cost of spawning (and connecting to Messages from) a new one every time.
+///
+/// The process is started on the first send and restarted on the next if it ever exits (or
+/// is killed for taking longer than `timeout` on a send). Each message is still sent by its
+/// own script, so failures are reported per recipient.
+pub struct ScriptSession {
+    timeout: Duration,
+    process: Option<SessionProcess>,
+}
+
+impl ScriptSession {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            process: None,
+        }
+    }
+
+    /// Sends a single message like [`send_message`], but through the session's process.
+    pub fn send_message(
+        &mut self,
+        message: &str,
+        number: &str,
+        service: &str,
+        attachment: Option<&str>,
+    ) -> Result<()> {
+        validate_service(service)?;
+        self.run(&buddy_script(
+            message,
+            number,
+            service,
+            attachment,
+            activation(),
+        ))
+    }
+
+    fn run(&mut self, apple_script: &str) -> Result<()> {
+        debug!(script = %apple_script, "Running AppleScript in osascript session");
+
+        let process = match &mut self.process {
+            Some(process) => process,
+            None => self.process.insert(SessionProcess::spawn()?),
+        };
+
+        match process.run(apple_script, self.timeout) {
+            Ok(reply) if reply == "ok" => Ok(()),
+            Ok(reply) => Err(AmsgError::AppleScriptFailed(
+                reply.strip_prefix("error ").unwrap_or(&reply).to_string(),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                if let Some(process) = self.process.take() {
+                    process.kill();
+                }
+
+                warn!(
+                    timeout = %humantime::format_duration(self.timeout),
+                    "Killed osascript session after a send timed out"
+                );
+                Err(AmsgError::Timeout(self.timeout))
+            }
+            // Failed runs are retried, by which point a fresh process will have been started
+            Err(e) => {
+                let stderr = self.process.take().map(SessionProcess::close);
+                Err(AmsgError::AppleScriptFailed(match stderr {
+                    Some(stderr) if !stderr.trim().is_empty() => stderr,
+                    _ => format!("osascript session exited unexpectedly: {}", e),
+                }))
+            }
+        }
+    }
+}
+
+// Reads one JSON-encoded AppleScript source per line from stdin, runs it in-process, and
+// replies on stdout with `ok` or `error` followed by the message and number `osascript`
+// itself would have printed
+const SESSION_SCRIPT: &str = r#"
+ObjC.import('Foundation');
+
+function run() {
+    const input = $.NSFileHandle.fileHandleWithStandardInput;
+    const output = $.NSFileHandle.fileHandleWithStandardOutput;
+    let buffer = '';
+
+    for (;;) {
+        const data = input.availableData;
+
+        if (data.length === 0) {
+            return;
+        }
+
+        buffer += $.NSString.alloc.initWithDataEncoding(data, $.NSUTF8StringEncoding).js;
+
+        for (let end = buffer.indexOf('\n'); end >= 0; end = buffer.indexOf('\n')) {
+            const source = JSON.parse(buffer.slice(0, end));
+            buffer = buffer.slice(end + 1);
+
+            const error = Ref();
+            const result = $.NSAppleScript.alloc.initWithSource(source).executeAndReturnError(error);
+            let reply = 'ok';
+
+            if (result.isNil()) {
+                const message = ObjC.unwrap(error[0].objectForKey('NSAppleScriptErrorMessage'));
+                const number = ObjC.unwrap(error[0].objectForKey('NSAppleScriptErrorNumber'));
+                reply = `error execution error: ${message} (${number})`.replace(/\n/g, ' ');
+            }
+
+            output.writeData($(reply + '\n').dataUsingEncoding($.NSUTF8StringEncoding));
+        }
+    }
+}
+"#;
+
+struct SessionProcess {
+    child: Child,
+    stdin: ChildStdin,
+    // Replies are read on a separate thread so that waiting for one can time out
+    replies: mpsc::Receiver<String>,
+    // Stderr is read as it is written (so that warnings cannot fill the pipe and block the
+    // process), keeping only the last few lines to report should the process exit
+    stderr: thread::JoinHandle<VecDeque<String>>,
+}
+
+// How many of the last lines a session writes to stderr are kept
+const SESSION_STDERR_LINES: usize = 20;
+
+impl SessionProcess {
+    fn spawn() -> Result<Self> {
+        let mut command = Command::new(osascript_path());
+        command
+            .arg("-l")
+            .arg("JavaScript")
+            .arg("-e")
+            .arg(SESSION_SCRIPT)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command
+            .spawn()
+            .map_err(AmsgError::io("Failed to start osascript session"))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let stderr = BufReader::new(child.stderr.take().expect("stderr is piped"));
+        let (tx, replies) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in stdout.lines().map_while(io::Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr = thread::spawn(move || {
+            let mut lines = VecDeque::with_capacity(SESSION_STDERR_LINES);
+
+            for line in stderr.lines().map_while(io::Result::ok) {
+                if lines.len() == SESSION_STDERR_LINES {
+                    lines.pop_front();
+                }
+
+                lines.push_back(line);
+            }
+
+            lines
+        });
+
+        Ok(Self {
+            child,
+            stdin,
+            replies,
+            stderr,
+        })
+    }
+
+    fn run(&mut self, apple_script: &str, timeout: Duration) -> io::Result<String> {
+        writeln!(self.stdin, "{}", encode_session_request(apple_script))?;
+        self.stdin.flush()?;
+
+        match self.replies.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply.trim_end().to_string()),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn kill(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    // Closing stdin lets the process exit on its own, after which the end of its stderr is
+    // returned
+    fn close(mut self) -> String {
+        drop(self.stdin);
+        let _ = self.child.wait();
+
+        self.stderr
+            .join()
+            .map(|lines| Vec::from(lines).join("\n"))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for ScriptSession {
+    fn drop(&mut self) {
+        if let Some(process) = self.process.take() {
+            process.close();
+        }
+    }
+}
+
+/// Something that sends single messages, letting the sending logic run against a stand-in for
+/// Messages (e.g., [`MockSender`]) as well as the real thing ([`OsascriptSender`]).
+///
+/// Senders are shared between the worker threads of a concurrent run.
+pub trait MessageSender: Sync {
+    /// Sends `message` to `number` via the given service, followed by an optional attachment
+    /// (given as an absolute path).
+    fn send(
+        &self,
+        message: &str,
+        number: &str,
+        service: &str,
+        attachment: Option<&str>,
+    ) -> Result<()>;
+}
+
+/// Sends messages through Messages with `osascript`, using one [`ScriptSession`] for each
+/// send in flight at once.
+pub struct OsascriptSender {
+    timeout: Duration,
+    template: Option<ScriptTemplate>,
+    sessions: Mutex<Vec<ScriptSession>>,
+}
+
+impl OsascriptSender {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            template: None,
+            sessions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sends with the given script instead of the built-in one.
+    pub fn with_template(mut self, template: ScriptTemplate) -> Self {
+        self.template = Some(template);
+        self
+    }
+}
+
+impl MessageSender for OsascriptSender {
+    fn send(
+        &self,
+        message: &str,
+        number: &str,
+        service: &str,
+        attachment: Option<&str>,
+    ) -> Result<()> {
+        // The service is the one value scripts take unquoted, so it must be a bare name
+        validate_service(service)?;
+
+        let idle = self
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let mut session = idle.unwrap_or_else(|| ScriptSession::new(self.timeout));
+        let result = match &self.template {
+            // Sending just the text would be silently wrong, so the recipient fails instead
+            Some(template) if attachment.is_some() && !template.has_attachment() => {
+                Err(AmsgError::InvalidArgument(format!(
+                    "Script template cannot send attachments without {}",
+                    ScriptTemplate::ATTACHMENT_PLACEHOLDER
+                )))
+            }
+            Some(template) => session.run(&template.render(message, number, service, attachment)),
+            None => session.send_message(message, number, service, attachment),
+        };
+
+        self.sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(session);
+        result
+    }
+}
+
+/// A user-supplied AppleScript to send each message with, in place of the built-in one.
+///
+/// The script must contain `{{service}}` (replaced by the service's name, as the built-in
+/// script uses it in `service type = {{service}}`), `{{number}}`, and `{{message}}`, the last
+/// two escaped for use between double quotes. `{{attachment}}` is likewise replaced by the
+/// attachment's path, or left empty if there is none.
+#[derive(Debug, Clone)]
+pub struct ScriptTemplate(String);
+
+impl ScriptTemplate {
+    /// The placeholders every template must contain.
+    pub const REQUIRED_PLACEHOLDERS: &[&str] = &["{{service}}", "{{number}}", "{{message}}"];
+    /// The placeholder for the attachment's path, which templates may leave out.
+    pub const ATTACHMENT_PLACEHOLDER: &str = "{{attachment}}";
+
+    pub fn load(path: &str) -> Result<Self> {
+        let script = fs::read_to_string(path).map_err(AmsgError::io(format!(
+            "Failed to read script template {}",
+            path
+        )))?;
+
+        Self::parse(script)
+            .map_err(|e| AmsgError::InvalidArgument(format!("Script template {}: {}", path, e)))
+    }
+
+    /// Checks that `script` contains each of the [`Self::REQUIRED_PLACEHOLDERS`].
+    pub fn parse(script: String) -> Result<Self> {
+        let missing = Self::REQUIRED_PLACEHOLDERS
+            .iter()
+            .filter(|placeholder| !script.contains(*placeholder))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            return Err(AmsgError::InvalidArgument(format!(
+                "missing placeholder(s) {}",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(Self(script))
+    }
+
+    /// Whether the template sends attachments (i.e., contains `{{attachment}}`).
+    pub fn has_attachment(&self) -> bool {
+        self.0.contains(Self::ATTACHMENT_PLACEHOLDER)
+    }
+
+    // Substituted in a single pass, so that a message containing a placeholder is sent as is
+    fn render(
+        &self,
+        message: &str,
+        number: &str,
+        service: &str,
+        attachment: Option<&str>,
+    ) -> String {
+        let mut script = String::with_capacity(self.0.len() + message.len());
+        let mut rest = self.0.as_str();
+
+        while let Some(start) = rest.find("{{") {
+            script.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let placeholder = rest.find("}}").map_or(rest, |end| &rest[..end + 2]);
+            let value = match placeholder {
+                "{{service}}" => service.to_string(),
+                "{{number}}" => escape_applescript_string(number),
+                "{{message}}" => escape_applescript_string(message),
+                "{{attachment}}" => escape_applescript_string(attachment.unwrap_or_default()),
+                _ => {
+                    script.push_str("{{");
+                    rest = &rest[2..];
+                    continue;
+                }
+            };
+
+            script.push_str(&value);
+            rest = &rest[placeholder.len()..];
+        }
+
+        script.push_str(rest);
+        script
+    }
+}
+
+/// A message passed to a [`MockSender`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub message: String,
+    pub number: String,
+    pub service: String,
+    pub attachment: Option<String>,
+}
+
+/// A [`MessageSender`] that records the messages it is given instead of sending them.
+///
+/// Sends to numbers passed to [`MockSender::unregistered`] fail the way Messages reports
+/// recipients not registered with a service, so fallbacks can be exercised too.
+#[derive(Default)]
+pub struct MockSender {
+    sent: Mutex<Vec<SentMessage>>,
+    unregistered: HashSet<(String, String)>,
+}
+
+impl MockSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails sends to `number` via `service` (e.g., to mimic a number without iMessage).
+    pub fn unregistered(mut self, number: &str, service: &str) -> Self {
+        self.unregistered
+            .insert((number.to_string(), service.to_ascii_lowercase()));
+        self
+    }
+
+    /// The messages successfully "sent" so far, in the order they were given.
+    pub fn sent(&self) -> Vec<SentMessage> {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl MessageSender for MockSender {
+    fn send(
+        &self,
+        message: &str,
+        number: &str,
+        service: &str,
+        attachment: Option<&str>,
+    ) -> Result<()> {
+        if self
+            .unregistered
+            .contains(&(number.to_string(), service.to_ascii_lowercase()))
+        {
+            return Err(AmsgError::AppleScriptFailed(String::from(
+                "execution error: Messages got an error: Can't get buddy. (-1728)",
+            )));
+        }
+
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(SentMessage {
+                message: message.to_string(),
+                number: number.to_string(),
+                service: service.to_string(),
+                attachment: attachment.map(String::from),
+            });
+        Ok(())
+    }
+}
+
+// Encodes an AppleScript source as a single line of ASCII-only JSON, so that neither line
+// breaks in the script nor multi-byte characters split across reads can garble it
+fn encode_session_request(apple_script: &str) -> String {
+    let json = Value::String(apple_script.to_string()).to_string();
+    let mut request = String::with_capacity(json.len());
+
+    for c in json.chars() {
+        if c.is_ascii() {
+            request.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                request.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+
+    request
+}
+
+// The message is escaped as a whole after placeholder substitution, so substituted values
+// (e.g., names containing quotes) cannot break out of the string literal. The service is left
+// unquoted (as `service type` takes a constant), so callers must check it with
+// `validate_service` first
+fn buddy_script(
+    message: &str,
+    number: &str,
+    service: &str,
+    attachment: Option<&str>,
+    activate: bool,
+) -> String {
+    format!(
+        r#"
+        tell application "Messages"
+            {activate}
+            set targetService to 1st service whose service type = {service}
+            set targetBuddy to buddy "{number}" of targetService
+            send "{message}" to targetBuddy
+            {send_attachment}
+        end tell
+        "#,
+        service = service,
+        number = escape_applescript_string(number),
+        message = escape_applescript_string(message),
+        send_attachment = send_attachment_script(attachment, "targetBuddy"),
+        activate = activate_script(activate)
+    )
+}
+
+fn group_script(message: &str, group: &str, attachment: Option<&str>, activate: bool) -> String {
+    format!(
+        r#"
+        tell application "Messages"
+            {activate}
+            set targetChat to 1st chat whose name is "{group}" or id is "{group}"
+            send "{message}" to targetChat
+            {send_attachment}
+        end tell
+        "#,
+        group = escape_applescript_string(group),
+        message = escape_applescript_string(message),
+        send_attachment = send_attachment_script(attachment, "targetChat"),
+        activate = activate_script(activate)
+    )
+}
+
+fn activate_script(activate: bool) -> &'static str {
+    if activate { "activate" } else { "" }
+}
+
+fn send_attachment_script(attachment: Option<&str>, target: &str) -> String {
+    attachment
+        .map(|path| {
+            format!(
+                r#"send POSIX file "{}" to {}"#,
+                escape_applescript_string(path),
+                target
+            )
+        })
+        .unwrap_or_default()
+}
+
+// The `osascript` binary that scripts are run with, looked up on `PATH` unless overridden
+static OSASCRIPT_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Runs every AppleScript with the given binary instead of the `osascript` on `PATH` (e.g.,
+/// a wrapper script, or a fake one in tests).
+///
+/// Only the first call has any effect, so this should be called before anything is sent.
+pub fn set_osascript_path(path: impl Into<PathBuf>) {
+    let _ = OSASCRIPT_PATH.set(path.into());
+}
+
+// Whether the built-in scripts bring Messages to the front before each send
+static ACTIVATE_MESSAGES: AtomicBool = AtomicBool::new(true);
+
+/// Sets whether the built-in scripts bring Messages to the front before each send, as they do
+/// by default.
+///
+/// Activating Messages steals focus on every send, but sends from the background may be less
+/// reliable. Script templates are run as given either way.
+pub fn set_activation(enabled: bool) {
+    ACTIVATE_MESSAGES.store(enabled, Ordering::Relaxed);
+}
+
+fn activation() -> bool {
+    ACTIVATE_MESSAGES.load(Ordering::Relaxed)
+}
+
+fn osascript_path() -> &'static Path {
+    OSASCRIPT_PATH
+        .get()
+        .map_or(Path::new("osascript"), PathBuf::as_path)
+}
+
+// Runs a script with `osascript`, returning whatever it prints
+fn run_apple_script(apple_script: String, timeout: Duration) -> Result<String> {
+    debug!(script = %apple_script, "Running AppleScript");
+
+    let mut command = Command::new(osascript_path());
+    command
+        .arg("-e")
+        .arg(apple_script)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // Keep a terminal's Ctrl-C from killing in-flight sends, leaving interrupts to the caller
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let mut child = command
+        .spawn()
+        .map_err(AmsgError::io("Failed to execute AppleScript"))?;
+
+    // `std::process` cannot wait with a timeout, but its output is only closed once `osascript`
+    // exits, so reading it to the end on another thread can be waited on instead. Scripts print
+    // little enough that reading stdout before stderr cannot fill the latter's pipe
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (mut output, mut errors) = (String::new(), String::new());
+        let _ = stdout.read_to_string(&mut output);
+        let _ = stderr.read_to_string(&mut errors);
+        let _ = tx.send((output, errors));
+    });
+
+    let Ok((stdout, stderr)) = rx.recv_timeout(timeout) else {
+        let _ = child.kill();
+        let _ = child.wait();
+
+        warn!(
+            timeout = %humantime::format_duration(timeout),
+            "Killed osascript after it timed out"
+        );
+        return Err(AmsgError::Timeout(timeout));
+    };
+
+    let status = child
+        .wait()
+        .map_err(AmsgError::io("Failed to execute AppleScript"))?;
+
+    if !status.success() {
+        return Err(AmsgError::AppleScriptFailed(stderr));
+    }
+
+    Ok(stdout)
+}
+
+/// Escapes a string for inclusion in a double-quoted AppleScript literal.
+///
+/// Windows-style `\r\n` line endings are normalized to `\n` so that they do not render as
+/// double line breaks in Messages.
+pub fn escape_applescript_string(message: &str) -> String {
+    let message = message.replace("\r\n", "\n");
+
+    // Escape backslashes first to avoid double-escaping later replacements
+    message
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name_placeholder() -> Vec<Placeholder> {
+        vec![parse_placeholder("{name}").unwrap()]
+    }
+
+    // Removes the recipients `filters` skips one at a time, returning those removed
+    fn apply_filters(
+        recipients: &mut Vec<Recipient>,
+        mut filters: RecipientFilters,
+    ) -> Vec<SkippedRecipient> {
+        let mut skipped = Vec::new();
+
+        for recipient in std::mem::take(recipients) {
+            match filters.skip_reason(&recipient.number).unwrap() {
+                Some(reason) => skipped.push(SkippedRecipient::new(recipient, reason)),
+                None => recipients.push(recipient),
+            }
+        }
+
+        skipped
+    }
+
+    #[test]
+    fn process_number_strips_formatting() {
+        assert_eq!(
+            process_number("+1 (234) 567-8910", &NumberOptions::default()).unwrap(),
+            "+12345678910"
+        );
+        assert_eq!(
+            process_number("(234) 567-8910", &NumberOptions::default()).unwrap(),
+            "2345678910"
+        );
+        assert_eq!(
+            process_number("  314159265  ", &NumberOptions::default()).unwrap(),
+            "314159265"
+        );
+    }
+
+    #[test]
+    fn process_number_enforces_length_bounds() {
+        assert_eq!(
+            process_number("1234567", &NumberOptions::default()).unwrap(),
+            "1234567"
+        );
+        assert_eq!(
+            process_number("+123456789012345", &NumberOptions::default()).unwrap(),
+            "+123456789012345"
+        );
+        assert!(process_number("123456", &NumberOptions::default()).is_err());
+        assert!(process_number("+1234567890123456", &NumberOptions::default()).is_err());
+        // Formatting characters do not count towards the length
+        assert!(process_number("12-34-56", &NumberOptions::default()).is_err());
+    }
+
+    #[test]
+    fn process_number_with_custom_length_bounds() {
+        let short_codes = NumberOptions {
+            min_digits: 5,
+            max_digits: 6,
+            ..NumberOptions::default()
+        };
+        assert_eq!(process_number("12345", &short_codes).unwrap(), "12345");
+        assert!(process_number("1234", &short_codes).is_err());
+        assert!(process_number("1234567", &short_codes).is_err());
+    }
+
+    #[test]
+    fn process_number_rejects_invalid_characters() {
+        assert!(process_number("123-ABC-4567", &NumberOptions::default()).is_err());
+        assert!(process_number("123.456.7890", &NumberOptions::default()).is_err());
+        assert!(process_number("++12345678910", &NumberOptions::default()).is_err());
+        assert!(process_number("1234567+", &NumberOptions::default()).is_err());
+        assert!(process_number("", &NumberOptions::default()).is_err());
+    }
+
+    #[test]
+    fn is_landline_only_for_fixed_line_numbers() {
+        assert!(is_landline("+441212345678"));
+        assert!(!is_landline("+447911123456"));
+        // US numbers may be either, so are not flagged
+        assert!(!is_landline("+15208782491"));
+        assert!(!is_landline("314159265"));
+    }
+
+    #[test]
+    fn process_number_treats_leading_00_as_plus() {
+        let us = NumberOptions {
+            country: Some(parse_country("US").unwrap()),
+            ..NumberOptions::default()
+        };
+
+        assert_eq!(
+            process_number("0044 20 7946 0958", &us).unwrap(),
+            "+442079460958"
+        );
+        assert_eq!(
+            process_number("00 44 (20) 7946-0958", &us).unwrap(),
+            "+442079460958"
+        );
+        // Without a country, numbers are only stripped of formatting
+        assert_eq!(
+            process_number("0044 20 7946 0958", &NumberOptions::default()).unwrap(),
+            "00442079460958"
+        );
+    }
+
+    #[test]
+    fn process_number_converts_vanity_numbers() {
+        let vanity = NumberOptions {
+            vanity: true,
+            ..NumberOptions::default()
+        };
+
+        assert_eq!(vanity_to_digits("1-800-Flowers"), "1-800-3569377");
+        assert_eq!(
+            process_number("1-800-FLOWERS", &vanity).unwrap(),
+            "18003569377"
+        );
+        assert!(process_number("1-800-FLOWERS", &NumberOptions::default()).is_err());
+        assert!(process_number("1-800-FLOWERS!", &vanity).is_err());
+    }
+
+    #[test]
+    fn errors_can_be_matched_by_kind() {
+        assert!(matches!(
+            process_number("12ab", &NumberOptions::default()),
+            Err(AmsgError::InvalidNumber { number, .. }) if number == "12ab"
+        ));
+        assert!(matches!(
+            validate_file_path("does/not/exist.csv", &["csv"]),
+            Err(AmsgError::FileNotFound(_))
+        ));
+        assert!(matches!(
+            parse_duration("-1s"),
+            Err(AmsgError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn is_email_requires_local_part_and_valid_domain() {
+        assert!(is_email("baron@murderpillow.com"));
+        assert!(is_email("john.a+stymers@mail.example.co.uk"));
+        assert!(!is_email("+1 (234) 567-8910"));
+        assert!(!is_email("@example.com"));
+        assert!(!is_email("baron@localhost"));
+        assert!(!is_email("baron@-example.com"));
+        assert!(!is_email("baron@example.c0m"));
+        assert!(!is_email("baron@@example.com"));
+        assert!(!is_email("bar on@example.com"));
+    }
+
+    #[test]
+    fn load_recipients_accepts_emails_and_numbers() {
+        let csv = "Alice,Alice@Example.com\nBob,+1 (234) 567-8910\nCarl,carl@\n".as_bytes();
+        let LoadedRecipients {
+            recipients,
+            skipped,
+            ..
+        } = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap();
+
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].number, "alice@example.com");
+        assert_eq!(recipients[1].number, "+12345678910");
+        assert_eq!(skipped.len(), 1);
+    }
+
+    #[test]
+    fn process_number_normalizes_to_e164_with_country() {
+        let us = &NumberOptions {
+            country: Some(country::US),
+            ..NumberOptions::default()
+        };
+        assert_eq!(
+            process_number("(234) 567-8910", us).unwrap(),
+            "+12345678910"
+        );
+        assert_eq!(
+            process_number("1 234 567 8910", us).unwrap(),
+            "+12345678910"
+        );
+        assert_eq!(
+            process_number("+44 20 7946 0958", us).unwrap(),
+            "+442079460958"
+        );
+        assert!(process_number("314159265", us).is_err());
+        assert!(process_number("+999 1234567", us).is_err());
+    }
+
+    #[test]
+    fn parse_country_is_case_insensitive() {
+        assert_eq!(parse_country("gb").unwrap(), country::GB);
+        assert_eq!(parse_country("US").unwrap(), country::US);
+        assert!(parse_country("XX").is_err());
+        assert!(parse_country("USA").is_err());
+    }
+
+    #[test]
+    fn read_recipients_with_names() {
+        let csv = "Baron von Murderpillow, +1 (234) 567-8910\n\"Stymers, John A.\",314159265\n"
+            .as_bytes();
+        let recipients = read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(
+            recipients[0].name.as_deref(),
+            Some("Baron von Murderpillow")
+        );
+        assert_eq!(recipients[0].number, "+1 (234) 567-8910");
+        assert_eq!(recipients[1].name.as_deref(), Some("Stymers, John A."));
+        assert_eq!(recipients[1].number, "314159265");
+    }
+
+    #[test]
+    fn read_recipients_without_names() {
+        let csv = "+1 (234) 567-8910\n314159265\n".as_bytes();
+        let recipients = read_recipients(csv, &CsvOptions::default(), &[]).unwrap();
+
+        assert_eq!(recipients.len(), 2);
+        assert!(recipients.iter().all(|r| r.name.is_none()));
+        assert!(recipients.iter().all(|r| r.attachment.is_none()));
+        assert_eq!(recipients[0].number, "+1 (234) 567-8910");
+        assert_eq!(recipients[1].number, "314159265");
+    }
+
+    #[test]
+    fn read_recipients_with_attachments() {
+        let csv = "Alice,+1 (234) 567-8910,flyer.png\nBob,314159265,\n".as_bytes();
+        let recipients = read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+
+        assert_eq!(recipients[0].attachment.as_deref(), Some("flyer.png"));
+        assert!(recipients[1].attachment.is_none());
+    }
+
+    #[test]
+    fn resolve_attachments_relative_to_base() {
+        let base = std::env::temp_dir().join("amsg-batch-attachment-test");
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("flyer.png"), b"").unwrap();
+
+        let csv = "Alice,+1 (234) 567-8910,flyer.png\nBob,314159265,\n".as_bytes();
+        let mut recipients =
+            read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+        for recipient in &mut recipients {
+            resolve_recipient_attachment(recipient, None, Some(&base)).unwrap();
+        }
+
+        let expected = fs::canonicalize(base.join("flyer.png")).unwrap();
+        assert_eq!(recipients[0].attachment.as_deref(), expected.to_str());
+        assert!(recipients[1].attachment.is_none());
+
+        assert!(matches!(
+            resolve_attachment_in("missing.png", Some(&base)),
+            Err(AmsgError::FileNotFound(path)) if path.ends_with("amsg-batch-attachment-test/missing.png")
+        ));
+        assert_eq!(
+            expand_home("~/flyer.png"),
+            Path::new(&env::var("HOME").unwrap()).join("flyer.png")
+        );
+        assert_eq!(expand_home("~flyer.png"), Path::new("~flyer.png"));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn read_recipients_with_custom_delimiter() {
+        let csv = "Alice;+1 (234) 567-8910\nBob, Jr.;314159265\n".as_bytes();
+        let options = CsvOptions {
+            delimiter: b';',
+            ..CsvOptions::default()
+        };
+        let recipients = read_recipients(csv, &options, &name_placeholder()).unwrap();
+
+        assert_eq!(recipients[1].name.as_deref(), Some("Bob, Jr."));
+        assert_eq!(recipients[1].number, "314159265");
+    }
+
+    #[test]
+    fn parse_delimiter_requires_single_ascii_character() {
+        assert_eq!(parse_delimiter(";").unwrap(), b';');
+        assert_eq!(parse_delimiter("\t").unwrap(), b'\t');
+        assert_eq!(parse_delimiter("\\t").unwrap(), b'\t');
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter(";;").is_err());
+        assert!(parse_delimiter("é").is_err());
+    }
+
+    #[test]
+    fn parse_datetime_requires_rfc_3339() {
+        let at = parse_datetime("2024-06-01T09:00:00-04:00").unwrap();
+        assert_eq!(at.to_utc().to_rfc3339(), "2024-06-01T13:00:00+00:00");
+        assert!(parse_datetime("2024-06-01 09:00").is_err());
+        assert!(parse_datetime("tomorrow").is_err());
+    }
+
+    #[test]
+    fn read_recipients_requires_number_column_with_names() {
+        let csv = "Baron von Murderpillow\n".as_bytes();
+        assert!(read_recipients(csv, &CsvOptions::default(), &name_placeholder()).is_err());
+    }
+
+    #[test]
+    fn load_recipients_skips_invalid_numbers() {
+        let csv = "Alice,+1 (234) 567-8910\nBob,12ab\n".as_bytes();
+        let LoadedRecipients {
+            recipients,
+            skipped,
+            ..
+        } = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap();
+
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].number, "+12345678910");
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].recipient.name.as_deref(), Some("Bob"));
+        assert_eq!(skipped[0].recipient.line, 2);
+    }
+
+    #[test]
+    fn strict_loading_fails_on_invalid_numbers() {
+        let csv = "Alice,+1 (234) 567-8910\nAl,12ab\n".as_bytes();
+        let error = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions {
+                strict: true,
+                ..NumberOptions::default()
+            },
+        )
+        .and_then(RecipientStream::load)
+        .err()
+        .unwrap();
+
+        assert!(
+            error
+                .to_string()
+                .starts_with("Invalid recipient on line 2:")
+        );
+    }
+
+    #[test]
+    fn validation_errors_are_ordered_by_line() {
+        let options = CsvOptions {
+            delay_column: Some(parse_column("3").unwrap()),
+            ..CsvOptions::default()
+        };
+        let csv = "Alice,314159265,soon\nBob\n".as_bytes();
+        let mut errors = ValidationErrors::default();
+
+        for recipient in read_source(csv, &options, &name_placeholder()).unwrap().1 {
+            if let Err(e) = recipient {
+                errors.push_error(&e);
+            }
+        }
+
+        errors.push(None, "Message is empty");
+        errors.push(Some(1), "Attachment does not exist");
+
+        let problems = errors
+            .problems()
+            .into_iter()
+            .map(|p| (p.line, p.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(problems[0].0, Some(1));
+        assert!(problems[0].1.starts_with("Invalid delay:"));
+        assert_eq!(problems[1], (Some(1), "Attachment does not exist"));
+        assert_eq!(
+            problems[2],
+            (Some(2), "Failed to get number from CSV record")
+        );
+        assert_eq!(problems[3], (None, "Message is empty"));
+        assert_eq!((errors.len(), errors.lines()), (4, 2));
+    }
+
+    #[test]
+    fn shuffle_recipients_is_reproducible_with_seed() {
+        let csv = (0..20)
+            .map(|i| format!("P{},+1 (234) 567-89{:02}\n", i, i))
+            .collect::<String>();
+        let shuffled = |seed| {
+            let mut recipients = stream_recipients(
+                csv.as_bytes(),
+                &CsvOptions::default(),
+                &name_placeholder(),
+                &NumberOptions::default(),
+            )
+            .and_then(RecipientStream::load)
+            .unwrap()
+            .recipients;
+            shuffle_recipients(&mut recipients, seed);
+            recipients.into_iter().map(|r| r.line).collect::<Vec<_>>()
+        };
+
+        assert_eq!(shuffled(Some(42)), shuffled(Some(42)));
+        assert_ne!(shuffled(Some(42)), (1..=20).collect::<Vec<_>>());
+
+        let mut lines = shuffled(None);
+        lines.sort();
+        assert_eq!(lines, (1..=20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_chooses_messages_stably_in_proportion() {
+        assert!(parse_split("70").is_err());
+        assert!(parse_split("70/0").is_err());
+        assert!(parse_split("70/thirty").is_err());
+        assert!(parse_split("4294967295/1").is_err());
+
+        let split = parse_split("70 / 30").unwrap();
+        assert_eq!(split.weights(), &[70, 30]);
+
+        let numbers = (0..1000)
+            .map(|i| format!("+1234567{:04}", i))
+            .collect::<Vec<_>>();
+        let choices = |seed| {
+            numbers
+                .iter()
+                .map(|number| split.choose(number, seed))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(choices(7), choices(7));
+        assert_ne!(choices(7), choices(8));
+
+        let first = choices(7).iter().filter(|&&choice| choice == 0).count();
+        assert!(
+            (620..=780).contains(&first),
+            "{} of 1000 got the first",
+            first
+        );
+    }
+
+    #[test]
+    fn mask_number_keeps_last_four_digits() {
+        assert_eq!(mask_number("+12345678910"), "+*******8910");
+        assert_eq!(mask_number("(234) 567-8910"), "(***) ***-8910");
+        assert_eq!(mask_number("123"), "123");
+        assert_eq!(mask_number("bob@example.com"), "b**@example.com");
+    }
+
+    #[test]
+    fn normalize_name_title_cases_words() {
+        assert_eq!(normalize_name("  JOHN   SMITH "), "John Smith");
+        assert_eq!(normalize_name("mary-jane o'brien"), "Mary-Jane O'Brien");
+        assert_eq!(normalize_name("MCDONALD"), "McDonald");
+        assert_eq!(normalize_name("Ronald MacKenzie"), "Ronald MacKenzie");
+        assert_eq!(normalize_name("ÉLODIE"), "Élodie");
+    }
+
+    #[test]
+    fn first_name_placeholder_uses_first_word_of_name() {
+        let csv = "Alice Liddell,+1 (234) 567-8910\nCher,314159265\n".as_bytes();
+        let recipients = read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+
+        assert_eq!(
+            recipients[0].render("Hi {firstname} ({name})"),
+            "Hi Alice (Alice Liddell)"
+        );
+        assert_eq!(recipients[1].render("Hi {firstname}"), "Hi Cher");
+
+        let csv = "Alice Liddell,+1 (234) 567-8910,Al\n".as_bytes();
+        let placeholders = vec![
+            parse_placeholder("{name}").unwrap(),
+            parse_placeholder("{firstname}=3").unwrap(),
+        ];
+        let recipients = read_recipients(csv, &CsvOptions::default(), &placeholders);
+        assert!(recipients.is_ok_and(|r| r[0].render("{firstname}") == "Al"));
+    }
+
+    #[test]
+    fn read_recipients_skips_comments_and_blank_records() {
+        let csv = "# Friends, family\nAlice,+1 (234) 567-8910\n\n , \n  #Work\nBob,314159265\n"
+            .as_bytes();
+        let recipients = read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+
+        let names = recipients
+            .iter()
+            .map(|r| r.name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [Some("Alice"), Some("Bob")]);
+        assert_eq!(recipients[1].line, 6);
+    }
+
+    #[test]
+    fn read_recipients_strips_byte_order_mark() {
+        let csv = "\u{FEFF}Name,Number\nAlice,+1 (234) 567-8910\n".as_bytes();
+        let options = CsvOptions {
+            has_header: true,
+            ..CsvOptions::default()
+        };
+        let placeholders = [parse_placeholder("{name}=Name").unwrap()];
+        let recipients = read_recipients(csv, &options, &placeholders).unwrap();
+
+        assert_eq!(recipients[0].fields["{name}"], "Alice");
+    }
+
+    #[test]
+    fn read_recipients_transcodes_latin1() {
+        let csv: &[u8] = b"Jos\xE9,+1 (234) 567-8910\n";
+        let options = CsvOptions {
+            encoding: parse_encoding("latin1").unwrap(),
+            ..CsvOptions::default()
+        };
+        let recipients = read_recipients(csv, &options, &name_placeholder()).unwrap();
+
+        assert_eq!(recipients[0].name.as_deref(), Some("José"));
+    }
+
+    #[test]
+    fn open_recipients_decompresses_gzip() {
+        use flate2::{Compression, write::GzEncoder};
+
+        let path = std::env::temp_dir().join("amsg-batch-gzip-test.csv.gz");
+        let path = path.to_str().unwrap();
+
+        let mut encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        encoder.write_all(b"Alice,+1 (234) 567-8910\n").unwrap();
+        encoder.finish().unwrap();
+
+        assert!(validate_file_path(path, RECIPIENT_EXTENSIONS).is_ok());
+        assert_eq!(RecipientFormat::from_path(path), RecipientFormat::Csv);
+
+        let options = CsvOptions::default();
+        let source = open_recipients(path, &options).unwrap();
+        let recipients = read_recipients(source, &options, &name_placeholder()).unwrap();
+
+        assert_eq!(recipients[0].name.as_deref(), Some("Alice"));
+        assert_eq!(recipients[0].number, "+1 (234) 567-8910");
+
+        // The whole suffix must match, so a compressed file is not taken for plain CSV
+        assert!(validate_file_path(path, &["csv"]).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn validate_file_path_explains_numbers_documents() {
+        let dir = std::env::temp_dir().join("amsg-batch-numbers-test");
+        let file = dir.join("clients.numbers");
+        let package = dir.join("package.numbers");
+        fs::create_dir_all(&package).unwrap();
+        File::create(&file).unwrap();
+
+        for path in [&file, &package] {
+            let e = validate_file_path(path.to_str().unwrap(), RECIPIENT_EXTENSIONS).unwrap_err();
+            assert!(e.to_string().contains("is an Apple Numbers document"));
+            assert!(e.to_string().contains("File > Export To > CSV"));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stream_recipients_reads_records_as_needed() {
+        struct Unreadable;
+
+        impl Read for Unreadable {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("unreadable"))
+            }
+        }
+
+        // Records before the unreadable part of the source are still yielded
+        let csv = "Alice,+1 (234) 567-8910\nBob,123\n"
+            .as_bytes()
+            .chain(Unreadable);
+        let number_options = NumberOptions::default();
+        let mut stream = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &number_options,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            stream.next(),
+            Some(Ok(LoadedRecipient::Valid(r))) if r.number == "+12345678910"
+        ));
+        assert!(matches!(
+            stream.next(),
+            Some(Ok(LoadedRecipient::Invalid(s))) if s.recipient.line == 2
+        ));
+        assert!(matches!(stream.next(), Some(Err(AmsgError::Csv { .. }))));
+    }
+
+    #[test]
+    fn dedupe_recipients_keeps_first_occurrence() {
+        let csv = "Alice,+1 (234) 567-8910\nBob,314159265\nAl,12345678910\n".as_bytes();
+        let mut recipients = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+        let duplicates = apply_filters(
+            &mut recipients,
+            RecipientFilters {
+                duplicates: Some(Duplicates::default()),
+                ..RecipientFilters::default()
+            },
+        );
+
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].name.as_deref(), Some("Alice"));
+        assert_eq!(recipients[1].name.as_deref(), Some("Bob"));
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].recipient.name.as_deref(), Some("Al"));
+    }
+
+    #[test]
+    fn filter_area_codes_compares_national_numbers() {
+        let csv = "A,+1 (415) 555-0100\nB,(212) 555-0100\nC,4155550101\nD,a@b.com\n".as_bytes();
+        let mut recipients = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions {
+                country: Some(country::US),
+                ..NumberOptions::default()
+            },
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+        let filtered = apply_filters(
+            &mut recipients,
+            RecipientFilters {
+                area_codes: vec![parse_area_code("(415)").unwrap()],
+                ..RecipientFilters::default()
+            },
+        );
+
+        let kept = recipients
+            .iter()
+            .map(|r| r.name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(kept, [Some("A"), Some("C")]);
+        assert_eq!(filtered.len(), 2);
+        assert!(parse_area_code("41a").is_err());
+        assert!(parse_area_code("").is_err());
+    }
+
+    #[test]
+    fn blocklist_matches_normalized_numbers() {
+        let path = std::env::temp_dir().join("amsg_batch_blocklist_test.txt");
+        fs::write(
+            &path,
+            "# Opted out\n+1 (234) 567-8910,STOP\n\n314159265\nBob@Example.com\n",
+        )
+        .unwrap();
+        let blocklist = Blocklist::load(path.to_str().unwrap(), &NumberOptions::default()).unwrap();
+
+        let csv = "A,12345678910\nB,314-159-265\nC,bob@example.com\nD,271828182\n".as_bytes();
+        let mut recipients = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+        let blocked = apply_filters(
+            &mut recipients,
+            RecipientFilters {
+                blocklist: Some(blocklist),
+                ..RecipientFilters::default()
+            },
+        );
+
+        assert_eq!(blocked.len(), 3);
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].name.as_deref(), Some("D"));
+
+        fs::write(&path, "12ab\n").unwrap();
+        assert!(Blocklist::load(path.to_str().unwrap(), &NumberOptions::default()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_output_writes_json_array() {
+        let path = std::env::temp_dir().join("amsg-batch-dry-run-test.json");
+        let path = path.to_str().unwrap();
+
+        let csv = "Alice,+1 (234) 567-8910\nBob,314159265\n".as_bytes();
+        let recipients = read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+        let mut output = DryRunOutput::create(path).unwrap();
+
+        for recipient in &recipients {
+            output
+                .record(recipient, "SMS", &recipient.render("Hi {name}"))
+                .unwrap();
+        }
+        output.finish().unwrap();
+
+        let written: Vec<Value> = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[1]["message"], "Hi Bob");
+        assert_eq!(written[1]["service"], "SMS");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn event_stream_writes_one_object_per_line() {
+        let csv = "Alice,+12345678901\n".as_bytes();
+        let recipient =
+            &read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap()[0];
+        let mut output = Vec::new();
+        let mut events = EventStream::new(&mut output);
+
+        events.record(recipient, Status::Sent, None).unwrap();
+        events
+            .record(recipient, Status::Failed, Some("boom\n"))
+            .unwrap();
+        events.finish(1, 1, 0).unwrap();
+
+        let events = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "send");
+        assert_eq!(events[0]["name"], "Alice");
+        assert_eq!(events[0]["status"], "sent");
+        assert!(events[0]["error"].is_null());
+        assert_eq!(events[1]["status"], "failed");
+        assert_eq!(events[1]["error"], "boom");
+        assert_eq!(events[2]["event"], "finish");
+        assert_eq!(events[2]["sent"], 1);
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let path = std::env::temp_dir().join("amsg-batch-checkpoint-test.json");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut checkpoint = Checkpoint::load(path).unwrap();
+        checkpoint.record("+12345678910").unwrap();
+        checkpoint.record("+12345678910").unwrap();
+
+        let csv = "Alice,+1 (234) 567-8910\nBob,314159265\n".as_bytes();
+        let mut recipients = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+        let sent = apply_filters(
+            &mut recipients,
+            RecipientFilters {
+                checkpoint: Some(Checkpoint::load(path).unwrap()),
+                ..RecipientFilters::default()
+            },
+        );
+
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].name.as_deref(), Some("Bob"));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].recipient.name.as_deref(), Some("Alice"));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "history")]
+    #[test]
+    fn history_skips_numbers_sent_within_window() {
+        let path = std::env::temp_dir().join("amsg-batch-history-test.sqlite");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut history = History::open(path, Some("spring")).unwrap();
+        history.record("+12345678901").unwrap();
+        // Sends recorded since opening are not counted, as they are part of the same run
+        assert!(
+            !history
+                .sent_within("+12345678901", Duration::from_secs(60))
+                .unwrap()
+        );
+        drop(history);
+
+        let history = History::open(path, None).unwrap();
+        assert!(
+            history
+                .sent_within("+12345678901", Duration::from_secs(60))
+                .unwrap()
+        );
+        assert!(
+            !history
+                .sent_within("+12345678902", Duration::from_secs(60))
+                .unwrap()
+        );
+
+        let stored: String = history
+            .connection
+            .query_row("SELECT number_hash FROM sends", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored.len(), 64);
+        assert!(!stored.contains("2345678901"));
+
+        let mut filters = RecipientFilters {
+            history: Some((history, Duration::from_secs(60))),
+            ..RecipientFilters::default()
+        };
+        assert_eq!(
+            filters.skip_reason("+12345678901").unwrap(),
+            Some(SkipReason::RecentlySent)
+        );
+        assert_eq!(filters.skip_reason("+12345678902").unwrap(), None);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn failure_log_round_trips_recipients() {
+        let path = std::env::temp_dir().join("amsg-batch-failures-test.csv");
+        let path = path.to_str().unwrap();
+
+        let csv = "Name;Number;City\nAlice;+1 (234) 567-8910;\"Toronto; ON\"\n".as_bytes();
+        let options = CsvOptions {
+            delimiter: b';',
+            has_header: true,
+            ..CsvOptions::default()
+        };
+        let placeholders = vec![
+            parse_placeholder("{name}").unwrap(),
+            parse_placeholder("{city}=City").unwrap(),
+        ];
+        let loaded = stream_recipients(csv, &options, &placeholders, &NumberOptions::default())
+            .and_then(RecipientStream::load)
+            .unwrap();
+
+        let mut failures = FailureLog::create(path, &options, loaded.header.as_ref()).unwrap();
+        failures.record(&loaded.recipients[0]).unwrap();
+
+        let retried = stream_recipients(
+            File::open(path).unwrap(),
+            &options,
+            &placeholders,
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].number, "+12345678910");
+        assert_eq!(retried[0].fields["{city}"], "Toronto; ON");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parse_placeholder_with_and_without_column() {
+        let name = parse_placeholder("{name}").unwrap();
+        assert_eq!(name.token, "{name}");
+        assert_eq!(name.column, Column::Name);
+
+        let city = parse_placeholder("{city}=3").unwrap();
+        assert_eq!(city.token, "{city}");
+        assert_eq!(city.column, Column::Index(2));
+
+        let amount = parse_placeholder("{amount}=Amount Due").unwrap();
+        assert_eq!(amount.column, Column::Header("Amount Due".to_string()));
+
+        assert!(parse_placeholder("{city}=0").is_err());
+        assert!(parse_placeholder("{city}=").is_err());
+        assert!(parse_placeholder("=3").is_err());
+    }
+
+    #[test]
+    fn read_recipients_with_column_placeholders() {
+        let csv = "Alice,314159265,Toronto,$20\n".as_bytes();
+        let placeholders = vec![
+            parse_placeholder("{name}").unwrap(),
+            parse_placeholder("{city}=3").unwrap(),
+            parse_placeholder("{amount}=4").unwrap(),
+        ];
+        let recipients = read_recipients(csv, &CsvOptions::default(), &placeholders).unwrap();
+
+        assert_eq!(recipients[0].fields["{name}"], "Alice");
+        assert_eq!(recipients[0].fields["{city}"], "Toronto");
+        assert_eq!(recipients[0].fields["{amount}"], "$20");
+        // The column after the numbers is bound to `{city}`, so it is not an attachment
+        assert!(recipients[0].attachment.is_none());
+    }
+
+    #[test]
+    fn fill_blank_fields_substitutes_default() {
+        let csv = "Alice,314159265\n,271828182\n".as_bytes();
+        let mut recipients =
+            read_recipients(csv, &CsvOptions::default(), &name_placeholder()).unwrap();
+
+        for recipient in &mut recipients {
+            recipient.fill_blank_fields("there");
+        }
+
+        let messages = recipients
+            .iter()
+            .map(|r| r.render("Hi {name}, {firstname}"))
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["Hi Alice, Alice", "Hi there, there"]);
+    }
+
+    #[test]
+    fn read_recipients_with_header() {
+        let csv = "Name,Number,City\nAlice,314159265,Toronto\n".as_bytes();
+        let options = CsvOptions {
+            has_header: true,
+            ..CsvOptions::default()
+        };
+        let placeholders = vec![
+            parse_placeholder("{name}").unwrap(),
+            parse_placeholder("{city}=City").unwrap(),
+        ];
+        let recipients = read_recipients(csv, &options, &placeholders).unwrap();
+
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].fields["{city}"], "Toronto");
+
+        let unknown = vec![parse_placeholder("{city}=Town").unwrap()];
+        assert!(read_recipients(csv, &options, &unknown).is_err());
+        assert!(read_recipients(csv, &CsvOptions::default(), &placeholders).is_err());
+    }
+
+    #[test]
+    fn read_recipients_with_message_column() {
+        let csv = "Alice,314159265,Happy birthday {name}!\nBob,271828182,\n".as_bytes();
+        let options = CsvOptions {
+            message_column: Some(parse_column("3").unwrap()),
+            ..CsvOptions::default()
+        };
+        let recipients = read_recipients(csv, &options, &name_placeholder()).unwrap();
+
+        assert!(recipients.iter().all(|r| r.attachment.is_none()));
+        assert_eq!(recipients[0].render("Hi {name}"), "Happy birthday Alice!");
+        assert_eq!(recipients[1].render("Hi {name}"), "Hi Bob");
+    }
+
+    #[test]
+    fn read_recipients_keeps_newlines_in_quoted_messages() {
+        let csv =
+            "Alice,314159265,\"Dear {name},\r\n\nSee you soon!\"\nBob,271828182,\n".as_bytes();
+        let options = CsvOptions {
+            message_column: Some(parse_column("3").unwrap()),
+            ..CsvOptions::default()
+        };
+        let recipients = read_recipients(csv, &options, &name_placeholder()).unwrap();
+
+        let message = recipients[0].render("Hi {name}");
+        assert_eq!(message, "Dear Alice,\r\n\nSee you soon!");
+        // Lines are counted from where each record starts, past the newlines in quoted cells
+        assert_eq!(recipients[1].line, 4);
+
+        let script = buddy_script(&message, &recipients[0].number, "iMessage", None, true);
+        assert!(script.contains(r#"send "Dear Alice,\n\nSee you soon!" to targetBuddy"#));
+    }
+
+    #[test]
+    fn load_recipients_with_service_column() {
+        let csv = "Alice,314159265,SMS\nBob,271828182,\nCarol,161803398,i Message\n".as_bytes();
+        let options = CsvOptions {
+            service_column: Some(parse_column("3").unwrap()),
+            ..CsvOptions::default()
+        };
+        let loaded = stream_recipients(
+            csv,
+            &options,
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap();
+
+        let services = loaded
+            .recipients
+            .iter()
+            .map(|r| r.service.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(services, [Some("SMS"), None]);
+        assert!(loaded.recipients.iter().all(|r| r.attachment.is_none()));
+        assert_eq!(loaded.skipped[0].recipient.name.as_deref(), Some("Carol"));
+    }
+
+    #[test]
+    fn read_recipients_with_delay_column() {
+        let options = CsvOptions {
+            delay_column: Some(parse_column("3").unwrap()),
+            ..CsvOptions::default()
+        };
+
+        let csv = "Alice,314159265,30s\nBob,271828182,\n".as_bytes();
+        let recipients = read_recipients(csv, &options, &name_placeholder()).unwrap();
+        let delays = recipients.iter().map(|r| r.delay).collect::<Vec<_>>();
+        assert_eq!(delays, [Some(Duration::from_secs(30)), None]);
+        assert!(recipients.iter().all(|r| r.attachment.is_none()));
+
+        let csv = "Alice,314159265,soon\n".as_bytes();
+        assert!(matches!(
+            read_recipients(csv, &options, &name_placeholder()),
+            Err(AmsgError::InvalidRecord { line: 1, reason }) if reason.starts_with("Invalid delay:")
+        ));
+    }
+
+    #[test]
+    fn read_recipients_with_segment_column() {
+        let options = CsvOptions {
+            has_header: true,
+            segment_column: Some(parse_column("Segment").unwrap()),
+            ..CsvOptions::default()
+        };
+
+        let csv = "Name,Number,Segment\nAlice,314159265, vip \nBob,271828182,\n".as_bytes();
+        let recipients = read_recipients(csv, &options, &name_placeholder()).unwrap();
+        let segments = recipients
+            .iter()
+            .map(|r| r.segment.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(segments, [Some("vip"), None]);
+        assert!(recipients.iter().all(|r| r.attachment.is_none()));
+
+        assert!(parse_segment_template("vip=vip.txt").is_ok_and(|t| t.segment == "vip"));
+        assert!(parse_segment_template("vip.txt").is_err());
+        assert!(parse_segment_template("=vip.txt").is_err());
+    }
+
+    #[test]
+    fn read_recipients_from_json() {
+        let json = r#"[
+            {"name": "Alice", "number": "+1 (234) 567-8910", "city": "Toronto", "age": 30},
+            {"number": 314159265}
+        ]"#
+        .as_bytes();
+        let options = CsvOptions {
+            format: RecipientFormat::Json,
+            ..CsvOptions::default()
+        };
+        let placeholders = vec![
+            parse_placeholder("{name}").unwrap(),
+            parse_placeholder("{city}=city").unwrap(),
+            parse_placeholder("{age}=age").unwrap(),
+        ];
+        let recipients = read_recipients(json, &options, &placeholders).unwrap();
+
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(
+            recipients[0].render("{name}, {age}, {city}"),
+            "Alice, 30, Toronto"
+        );
+        assert_eq!(recipients[1].name, None);
+        assert_eq!(recipients[1].number, "314159265");
+        assert_eq!(recipients[1].fields["{city}"], "");
+
+        let by_index = vec![parse_placeholder("{city}=3").unwrap()];
+        assert!(read_recipients(json, &options, &by_index).is_err());
+    }
+
+    #[test]
+    fn read_recipients_from_vcard() {
+        let vcf = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Smith\\, Alice\r\nORG:Acme\r\n\
+                   item1.TEL;TYPE=CELL:+1 (234) 567-8910\r\nTEL;TYPE=HOME:314159265\r\n\
+                   END:VCARD\r\nBEGIN:VCARD\r\nVERSION:4.0\r\nN:Jones;Bob;;;\r\n\
+                   TEL;VALUE=uri:tel:+1-271-\r\n 828-1828\r\nEND:VCARD\r\n\
+                   BEGIN:VCARD\r\nFN:No Number\r\nEND:VCARD\r\n"
+            .as_bytes();
+        let options = CsvOptions {
+            format: RecipientFormat::Vcard,
+            ..CsvOptions::default()
+        };
+        let placeholders = [
+            parse_placeholder("{name}").unwrap(),
+            parse_placeholder("{company}=org").unwrap(),
+        ];
+
+        let recipients = read_recipients(vcf, &options, &placeholders).unwrap();
+        let summary = recipients
+            .iter()
+            .map(|r| (r.name.as_deref(), r.number.as_str(), r.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (Some("Smith, Alice"), "+1 (234) 567-8910", 1),
+                (Some("Bob Jones"), "+1-271-828-1828", 8)
+            ]
+        );
+        assert_eq!(
+            recipients[0].render("Hi {name} of {company}"),
+            "Hi Smith, Alice of Acme"
+        );
+
+        let options = CsvOptions {
+            all_numbers: true,
+            ..options
+        };
+        let recipients = read_recipients(vcf, &options, &placeholders).unwrap();
+        assert_eq!(recipients.len(), 3);
+        assert_eq!(recipients[1].number, "314159265");
+    }
+
+    #[test]
+    fn render_message_substitutes_in_a_single_pass() {
+        let fields = HashMap::from([
+            ("{name}".to_string(), "Sneaky {name} {city}".to_string()),
+            ("{city}".to_string(), "Toronto".to_string()),
+            ("{n}".to_string(), "short".to_string()),
+        ]);
+
+        assert_eq!(
+            render_message("Hi {name} from {city}, {n}!", &fields),
+            "Hi Sneaky {name} {city} from Toronto, short!"
+        );
+        assert_eq!(
+            render_message("No placeholders", &fields),
+            "No placeholders"
+        );
+    }
+
+    #[test]
+    fn spin_message_picks_an_option_of_each_group() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut seen = HashSet::new();
+
+        for _ in 0..100 {
+            let spun = spin_message("{Hi|{Hello|Hey} there} {name}!", &mut rng);
+            assert!(
+                ["Hi {name}!", "Hello there {name}!", "Hey there {name}!"].contains(&spun.as_str())
+            );
+            seen.insert(spun);
+        }
+
+        assert_eq!(seen.len(), 3);
+        assert!(["ab", "b"].contains(&spin_message("{a|}b", &mut rng).as_str()));
+        assert_eq!(spin_message("{{name}|{name}}", &mut rng), "{name}");
+        assert_eq!(
+            spin_message("Unmatched { and } {x|x", &mut rng),
+            "Unmatched { and } {x|x"
+        );
+    }
+
+    #[test]
+    fn spin_message_for_repeats_its_picks() {
+        let template = "{Hi|Hello|Hey} {there|friend}";
+        let spun = (0..50)
+            .map(|number| spin_message_for(template, &format!("+1234567{:04}", number), 7))
+            .collect::<Vec<_>>();
+
+        for (number, spun) in spun.iter().enumerate() {
+            assert_eq!(
+                &spin_message_for(template, &format!("+1234567{:04}", number), 7),
+                spun
+            );
+        }
+
+        // Recipients are still spread across the options
+        assert_eq!(spun.iter().collect::<HashSet<_>>().len(), 6);
+    }
+
+    #[test]
+    fn may_render_blank_considers_every_option() {
+        let mut fields = HashMap::new();
+        fields.insert(String::from("{name}"), String::new());
+
+        assert!(may_render_blank("{|Hi}", &fields));
+        assert!(may_render_blank("{Hi|{ |{name}}} {name}", &fields));
+        assert!(!may_render_blank("{Hi|Hello} {name}", &fields));
+        assert!(!may_render_blank("{|Hi} there", &fields));
+
+        fields.insert(String::from("{name}"), String::from("Alice"));
+        assert!(!may_render_blank("{|{name}}{name}", &fields));
+    }
+
+    #[test]
+    fn find_brace_tokens_skips_spintax() {
+        assert_eq!(
+            find_brace_tokens("{Hi|Hey {name}} {nmae}, {} {name} {{city}} {x|y}"),
+            ["{name}", "{nmae}", "{city}"]
+        );
+        assert!(find_brace_tokens("No tokens { here").is_empty());
+    }
+
+    #[test]
+    fn append_footer_skips_messages_already_ending_with_it() {
+        let footer = "Reply STOP to unsubscribe";
+
+        assert_eq!(
+            append_footer(String::from("Hi Bob!\n"), footer),
+            "Hi Bob!\nReply STOP to unsubscribe"
+        );
+        assert_eq!(
+            append_footer(String::from("Hi!\nReply STOP to unsubscribe\n"), footer),
+            "Hi!\nReply STOP to unsubscribe\n"
+        );
+        assert_eq!(append_footer(String::from("Hi!"), ""), "Hi!");
+    }
+
+    #[test]
+    fn rate_limiter_spaces_out_sends() {
+        let delay = Duration::from_millis(50);
+        let limiter = RateLimiter::new(delay);
+        let start = Instant::now();
+
+        limiter.acquire();
+        assert!(start.elapsed() < delay);
+        limiter.release();
+
+        limiter.acquire();
+        assert!(start.elapsed() >= delay);
+    }
+
+    #[test]
+    fn rate_limiter_pauses_between_batches() {
+        let pause = Duration::from_millis(50);
+        let limiter = RateLimiter::new(Duration::ZERO).with_batches(2, pause);
+        let start = Instant::now();
+
+        for _ in 0..2 {
+            limiter.acquire();
+            limiter.release();
+        }
+        assert!(start.elapsed() < pause);
+
+        limiter.acquire();
+        assert!(start.elapsed() >= pause);
+    }
+
+    #[test]
+    fn rate_limiter_adapts_to_failures() {
+        let delay = Duration::from_secs(2);
+        let limiter = RateLimiter::new(delay).with_adaptive(Duration::from_secs(10));
+        assert_eq!(limiter.next_delay(None), delay);
+
+        limiter.adapt(true);
+        assert_eq!(limiter.next_delay(None), Duration::from_secs(4));
+        limiter.adapt(true);
+        assert_eq!(limiter.next_delay(None), Duration::from_secs(6));
+
+        // The delay never grows past the cap, however many sends fail
+        for _ in 0..10 {
+            limiter.adapt(true);
+        }
+        assert_eq!(limiter.next_delay(None), Duration::from_secs(10));
+
+        limiter.adapt(false);
+        assert_eq!(limiter.next_delay(None), Duration::from_secs(8));
+
+        for _ in 0..100 {
+            limiter.adapt(false);
+        }
+        assert_eq!(limiter.next_delay(None), delay);
+
+        // Without adaptive delays, outcomes make no difference
+        let limiter = RateLimiter::new(delay);
+        limiter.adapt(true);
+        assert_eq!(limiter.next_delay(None), delay);
+    }
+
+    #[test]
+    fn send_latency_uses_nearest_rank_percentiles() {
+        assert_eq!(SendLatency::from_samples(&[]), None);
+
+        let samples = (1..=20)
+            .rev()
+            .map(Duration::from_millis)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            SendLatency::from_samples(&samples),
+            Some(SendLatency {
+                min: Duration::from_millis(1),
+                median: Duration::from_millis(10),
+                p95: Duration::from_millis(19),
+                max: Duration::from_millis(20),
+            })
+        );
+
+        let one = [Duration::from_millis(250)];
+        let latency = SendLatency::from_samples(&one).unwrap();
+        assert_eq!((latency.min, latency.p95), (one[0], one[0]));
+    }
+
+    #[test]
+    fn estimate_remaining_scales_by_rate() {
+        let elapsed = Duration::from_secs(30);
+
+        assert_eq!(estimate_remaining(elapsed, 0, 100), None);
+        assert_eq!(
+            estimate_remaining(elapsed, 10, 100),
+            Some(Duration::from_secs(270))
+        );
+        assert_eq!(estimate_remaining(elapsed, 100, 100), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn rate_limiter_jitter_stays_within_bounds() {
+        let delay = Duration::from_millis(100);
+        let limiter = RateLimiter::new(delay).with_jitter(0.2);
+
+        for _ in 0..100 {
+            let jittered = limiter.next_delay(None);
+            assert!(jittered >= delay.mul_f64(0.8) && jittered <= delay.mul_f64(1.2));
+        }
+    }
+
+    #[test]
+    fn session_requests_are_single_ascii_lines() {
+        let script = "send \"Héllo 👋\"\nend tell";
+        let request = encode_session_request(script);
+
+        assert!(request.is_ascii() && !request.contains('\n'));
+        assert_eq!(serde_json::from_str::<String>(&request).unwrap(), script);
+    }
+
+    #[test]
+    fn session_script_has_no_line_breaks_inside_literals() {
+        // A raw line break would end a JavaScript string (or regex) literal early, so every
+        // quote must be closed on the line it opens
+        for line in SESSION_SCRIPT.lines() {
+            for quote in ['\'', '`'] {
+                assert_eq!(
+                    line.matches(quote).count() % 2,
+                    0,
+                    "unclosed {quote} in {line}"
+                );
+            }
+        }
+
+        // Line breaks are escaped where the script splits and writes lines instead
+        assert_eq!(SESSION_SCRIPT.matches(r"'\n'").count(), 3);
+        assert!(SESSION_SCRIPT.contains(r".replace(/\n/g, ' ')"));
+    }
+
+    #[test]
+    fn buddy_script_escapes_names_with_quotes() {
+        let csv = "\"Bob \"\"The Boss\"\" Smith\",+1 (234) 567-8910\n".as_bytes();
+        let recipients = stream_recipients(
+            csv,
+            &CsvOptions::default(),
+            &name_placeholder(),
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+        let recipient = &recipients[0];
+        assert_eq!(recipient.name.as_deref(), Some("Bob \"The Boss\" Smith"));
+
+        let message = render_message("Hi {name}!", &recipient.fields);
+        let script = buddy_script(&message, &recipient.number, "iMessage", None, true);
+
+        assert!(script.contains(r#"send "Hi Bob \"The Boss\" Smith!" to targetBuddy"#));
+    }
+
+    // Reads the AppleScript string literal that `script` continues with after `prefix`,
+    // returning its unescaped contents and whatever follows the closing quote
+    fn apple_script_literal<'a>(script: &'a str, prefix: &str) -> (String, &'a str) {
+        let start = script.find(prefix).expect("script contains the prefix") + prefix.len();
+        let mut chars = script[start..].char_indices();
+        let mut literal = String::new();
+
+        assert_eq!(chars.next(), Some((0, '"')));
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return (literal, &script[start + i + 1..]),
+                '\\' => literal.push(match chars.next().expect("escape is complete").1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    c => c,
+                }),
+                c => literal.push(c),
+            }
+        }
+
+        panic!("string literal is never closed");
+    }
+
+    #[test]
+    fn scripts_keep_hostile_values_inside_their_literals() {
+        let hostile = [
+            r#""& do shell script "rm -rf ~""#,
+            r#"\" & (do shell script "id") & "\"#,
+            "Bob\\",
+            "Bob\r\nend tell\ndo shell script \"id\"\n--",
+            "«event sysoexec» \u{201C}smart\u{201D} ¬\n\t\u{2028}",
+        ];
+
+        for name in hostile {
+            let mut fields = HashMap::new();
+            fields.insert(String::from("{name}"), name.to_string());
+            let message = render_message("Hi {name}!", &fields);
+            let expected = message.replace("\r\n", "\n");
+
+            let script = buddy_script(&message, name, "iMessage", Some(name), true);
+            let (sent, rest) = apple_script_literal(&script, "send ");
+            assert_eq!(sent, expected);
+            assert!(rest.starts_with(" to targetBuddy\n"));
+
+            let (buddy, rest) = apple_script_literal(&script, "buddy ");
+            assert_eq!(buddy, name.replace("\r\n", "\n"));
+            assert!(rest.starts_with(" of targetService\n"));
+
+            let (path, rest) = apple_script_literal(&script, "send POSIX file ");
+            assert_eq!(path, name.replace("\r\n", "\n"));
+            assert!(rest.starts_with(" to targetBuddy\n"));
+
+            let script = group_script(&message, name, None, true);
+            let (sent, rest) = apple_script_literal(&script, "send ");
+            assert_eq!(sent, expected);
+            assert!(rest.starts_with(" to targetChat\n"));
+
+            let template = ScriptTemplate::parse(String::from(
+                r#"send "{{message}}" to buddy "{{number}}" of service type {{service}}"#,
+            ))
+            .unwrap();
+            let script = template.render(&message, name, "SMS", None);
+            let (sent, rest) = apple_script_literal(&script, "send ");
+            assert_eq!(sent, expected);
+            assert!(rest.starts_with(" to buddy "));
+
+            let (buddy, rest) = apple_script_literal(&script, "buddy ");
+            assert_eq!(buddy, name.replace("\r\n", "\n"));
+            assert_eq!(rest, " of service type SMS");
+        }
+    }
+
+    #[test]
+    fn senders_reject_services_that_are_not_bare_names() {
+        let sender = OsascriptSender::new(Duration::from_secs(1));
+
+        for service in [
+            "iMessage\ndo shell script \"id\"",
+            "SMS -- comment",
+            "\"iMessage\"",
+        ] {
+            // Rejected before `osascript` is ever run
+            assert!(matches!(
+                sender.send("Hi", "+12345678901", service, None),
+                Err(AmsgError::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn scripts_only_activate_messages_if_asked() {
+        let activates = |script: String| script.lines().any(|line| line.trim() == "activate");
+
+        assert!(activates(buddy_script(
+            "Hi",
+            "314159265",
+            "SMS",
+            None,
+            true
+        )));
+        assert!(!activates(buddy_script(
+            "Hi",
+            "314159265",
+            "SMS",
+            None,
+            false
+        )));
+        assert!(activates(group_script("Hi", "Team", None, true)));
+        assert!(!activates(group_script("Hi", "Team", None, false)));
+    }
+
+    #[test]
+    fn script_template_requires_placeholders() {
+        let e =
+            ScriptTemplate::parse(String::from("send \"{{message}}\" to {{number}}")).unwrap_err();
+        assert_eq!(e.to_string(), "missing placeholder(s) {{service}}");
+
+        let template = ScriptTemplate::parse(String::from(
+            "{{service}} {{number}} \"{{message}}\" \"{{attachment}}\" {{other}}",
+        ))
+        .unwrap();
+        assert!(template.has_attachment());
+        assert_eq!(
+            template.render("Say \"{{number}}\"", "+12345678901", "SMS", None),
+            r#"SMS +12345678901 "Say \"{{number}}\"" "" {{other}}"#
+        );
+    }
+
+    #[test]
+    fn mock_sender_records_sends_and_fallbacks() {
+        let sender = MockSender::new().unregistered("+12345678901", "iMessage");
+        let send = |number| {
+            send_with_retries(
+                number,
+                "iMessage",
+                Some("SMS"),
+                0,
+                Duration::ZERO,
+                |service| sender.send("Hi", number, service, None),
+            )
+        };
+
+        assert_eq!(send("+12345678901").unwrap(), "SMS");
+        assert_eq!(send("+12345678902").unwrap(), "iMessage");
+        assert_eq!(
+            sender
+                .sent()
+                .iter()
+                .map(|m| (m.number.as_str(), m.service.as_str()))
+                .collect::<Vec<_>>(),
+            [("+12345678901", "SMS"), ("+12345678902", "iMessage")]
+        );
+    }
+
+    #[test]
+    fn send_batch_records_each_outcome() {
+        let path = std::env::temp_dir().join("amsg-batch-batch-report-test.csv");
+        let path = path.to_str().unwrap();
+
+        let csv = "Alice,+12345678901\nBob,+12345678902\nCarol,+12345678903\nAlice,+12345678901\n";
+        let mut recipients = stream_recipients(
+            csv.as_bytes(),
+            &CsvOptions::default(),
+            &[parse_placeholder("{name}").unwrap()],
+            &NumberOptions::default(),
+        )
+        .and_then(RecipientStream::load)
+        .unwrap()
+        .recipients;
+        let skipped = apply_filters(
+            &mut recipients,
+            RecipientFilters {
+                duplicates: Some(Duplicates::default()),
+                ..RecipientFilters::default()
+            },
+        );
+
+        // Alice falls back on SMS, while Carol has neither service
+        let sender = MockSender::new()
+            .unregistered("+12345678901", "iMessage")
+            .unregistered("+12345678903", "iMessage")
+            .unregistered("+12345678903", "SMS");
+        let progress = AtomicUsize::new(0);
+        let options = BatchOptions {
+            service: "iMessage",
+            fallback_service: Some("SMS"),
+            max_retries: 0,
+            retry_base_delay: Duration::ZERO,
+            concurrency: 1,
+            limiter: RateLimiter::new(Duration::ZERO),
+            dry_run: false,
+            render: &|recipient| format!("Hi {}", recipient.name.as_deref().unwrap_or_default()),
+            interrupted: &AtomicBool::new(false),
+            progress: &|| {
+                progress.fetch_add(1, Ordering::SeqCst);
+            },
+        };
+        let mut records = Records {
+            report: Some(Report::create(path, false, false, false).unwrap()),
+            ..Records::default()
+        };
+
+        records.record_skipped(&skipped).unwrap();
+        let tally = send_batch(
+            &sender,
+            recipients.into_iter().map(Ok),
+            3,
+            &options,
+            &mut records,
+        )
+        .unwrap();
+        drop(records);
+
+        assert_eq!((tally.sent, tally.failed), (2, 1));
+        assert_eq!(tally.latencies.len(), 3);
+        assert_eq!(progress.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            sender
+                .sent()
+                .iter()
+                .map(|m| (m.message.as_str(), m.number.as_str(), m.service.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("Hi Alice", "+12345678901", "SMS"),
+                ("Hi Bob", "+12345678902", "iMessage")
+            ]
+        );
+
+        let report = fs::read_to_string(path).unwrap();
+        let rows = report
+            .lines()
+            .map(|line| line.split(',').take(3).collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                "name,number,status",
+                "Alice,+12345678901,skipped",
+                "Alice,+12345678901,sent",
+                "Bob,+12345678902,sent",
+                "Carol,+12345678903,failed"
+            ]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn amsg_error_detects_unregistered_recipients() {
+        let unregistered = AmsgError::AppleScriptFailed(String::from(
+            "execution error: Messages got an error: Can’t get buddy id \"+12345678910\". (-1728)",
+        ));
+        assert!(unregistered.is_not_registered());
+        assert!(
+            !AmsgError::AppleScriptFailed(String::from("execution error: (-1700)"))
+                .is_not_registered()
+        );
+    }
+
+    #[test]
+    fn amsg_error_explains_apple_script_errors() {
+        let unregistered = AmsgError::AppleScriptFailed(String::from(
+            "execution error: Messages got an error: Can’t get buddy id \"+12345678910\". (-1728)\n",
+        ));
+        assert_eq!(unregistered.apple_script_error_code(), Some(-1728));
+        assert_eq!(
+            unregistered.to_string(),
+            "AppleScript execution failed: buddy not found (the recipient is not registered with \
+             the service) (-1728)"
+        );
+
+        let unknown = AmsgError::AppleScriptFailed(String::from("execution error: huh (-42)\n"));
+        assert_eq!(unknown.apple_script_error_code(), Some(-42));
+        assert_eq!(
+            unknown.to_string(),
+            "AppleScript execution failed: execution error: huh (-42)"
+        );
+
+        let garbled = AmsgError::AppleScriptFailed(String::from("something went wrong"));
+        assert_eq!(garbled.apple_script_error_code(), None);
+    }
+
+    #[test]
+    fn sms_segments_depends_on_encoding() {
+        assert_eq!(sms_segments(""), 1);
+        assert_eq!(sms_segments(&"a".repeat(160)), 1);
+        assert_eq!(sms_segments(&"a".repeat(161)), 2);
+        assert_eq!(sms_segments(&"a".repeat(306)), 2);
+        assert_eq!(sms_segments(&"€".repeat(80)), 1);
+        assert_eq!(sms_segments(&"€".repeat(81)), 2);
+        assert_eq!(sms_segments(&"é".repeat(160)), 1);
+        assert_eq!(sms_segments(&"ê".repeat(70)), 1);
+        assert_eq!(sms_segments(&"ê".repeat(71)), 2);
+        assert_eq!(sms_segments(&"🙂".repeat(35)), 1);
+    }
+
+    #[test]
+    fn expand_env_vars_leaves_unset_and_malformed_names() {
+        let lookup = |name: &str| (name == "COMPANY").then(|| String::from("Acme"));
+
+        let (expanded, unset) = expand_env_vars(
+            "Hi {name}, from ${COMPANY} (${COMPANY}) and ${TEAM}: ${1X} $5 ${ } ${COMPANY",
+            lookup,
+        );
+        assert_eq!(
+            expanded,
+            "Hi {name}, from Acme (Acme) and ${TEAM}: ${1X} $5 ${ } ${COMPANY"
+        );
+        assert_eq!(unset, ["TEAM"]);
+    }
+
+    #[test]
+    fn escape_applescript_string_escapes_control_characters() {
+        assert_eq!(escape_applescript_string("a\nb"), "a\\nb");
+        assert_eq!(escape_applescript_string("a\tb"), "a\\tb");
+        assert_eq!(escape_applescript_string("a\rb"), "a\\rb");
+        assert_eq!(escape_applescript_string("a\r\nb\r\n"), "a\\nb\\n");
+        assert_eq!(escape_applescript_string("a\n\rb"), "a\\n\\rb");
+    }
+
+    #[test]
+    fn escape_applescript_string_escapes_special_characters() {
+        assert_eq!(
+            escape_applescript_string("Say \"hi\"\\\n"),
+            "Say \\\"hi\\\"\\\\\\n"
+        );
+    }
+}
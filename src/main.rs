@@ -5,28 +5,119 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+#[cfg(feature = "history")]
+use amsg_batch::History;
+use amsg_batch::{
+    BatchOptions, Blocklist, Checkpoint, Column, CsvOptions, DisplayNumber, DryRunOutput,
+    Duplicates, EventStream, FailureLog, INDEX_PLACEHOLDER, LoadedRecipient, LoadedRecipients,
+    MAX_NUMBER_LENGTH, MIN_NUMBER_LENGTH, MessageSender, NumberOptions, OsascriptSender,
+    Placeholder, RECIPIENT_EXTENSIONS, RateLimiter, Recipient, RecipientFilters, RecipientFormat,
+    Records, Report, STDIN_PATH, ScriptTemplate, SegmentTemplate, SendLatency, SkipReason,
+    SkippedRecipient, Split, Status, Tally, ValidationErrors, append_footer, available_services,
+    check_apple_script, check_service_available, ensure_messages_running, expand_env_vars,
+    find_brace_tokens, is_email, is_landline, may_render_blank, open_recipients, parse_area_code,
+    parse_column, parse_country, parse_datetime, parse_delimiter, parse_duration, parse_encoding,
+    parse_placeholder, parse_segment_template, parse_split, process_number, quit_messages,
+    read_message, render_message, resolve_attachment, resolve_recipient_attachment, retry,
+    send_batch, send_group_message, send_with_retries, set_activation, set_number_masking,
+    set_osascript_path, shuffle_recipients, sms_segments, spin_message, spin_message_for,
+    stream_recipients, validate_file_path, validate_service,
+};
+#[cfg(feature = "webhook")]
+use amsg_batch::{RunSummary, post_webhook};
 use anyhow::{Context, Result, bail};
-use clap::Parser;
-use csv::ReaderBuilder;
-use std::{fs, path::Path, process::Command, thread, time::Duration};
-use tracing::{error, info, warn};
+use chrono::{DateTime, Local};
+use clap::{
+    Arg, ArgAction, ArgGroup, Args as _, CommandFactory, Parser, Subcommand, ValueEnum,
+    parser::ValueSource,
+};
+use clap_complete::Shell;
+use encoding_rs::{Encoding, UTF_8};
+use phonenumber::country;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fmt, fs,
+    io::{self, IsTerminal, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use toml::{Table, Value as TomlValue};
+use tracing::{
+    Event, Subscriber, error,
+    field::{Field, Visit},
+    info, info_span,
+    level_filters::LevelFilter,
+    warn,
+};
+use tracing_indicatif::{IndicatifLayer, span_ext::IndicatifSpanExt, style::ProgressStyle};
+use tracing_subscriber::{
+    Layer,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields,
+        format::{Format, Writer},
+        writer::BoxMakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
 
 const DEFAULT_SERVICE: &str = "iMessage";
-const DELAY: Duration = Duration::from_millis(1000);
-const MIN_NUMBER_LENGTH: usize = 7;
-const MAX_NUMBER_LENGTH: usize = 15;
+const DEFAULT_DELAY: &str = "1s";
+const DEFAULT_MAX_DELAY: &str = "1m";
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: &str = "2s";
+const DEFAULT_SEND_TIMEOUT: &str = "30s";
+const DEFAULT_CONCURRENCY: u32 = 1;
+const DEFAULT_CONFIG_PATH: &str = "amsg-batch.toml";
+// Read when `--osascript-path` is not given
+const OSASCRIPT_PATH_ENV: &str = "AMSG_OSASCRIPT";
+// Read when neither `--verbose` nor `--quiet` is given
+const LOG_LEVEL_ENV: &str = "RUST_LOG";
+// Turns off colors when set to anything but an empty string (see https://no-color.org)
+const NO_COLOR_ENV: &str = "NO_COLOR";
+// How many messages are sent between each log of the estimated time remaining
+const PROGRESS_TEMPLATE: &str = "{wide_bar} {pos}/{len} ({percent}%) ETA {eta}";
+
+// Exit codes other than 0 (success) and 1 (any error, which `anyhow` reports on its own)
+const EXIT_SEND_FAILED: u8 = 3;
+const EXIT_NO_RECIPIENTS: u8 = 4;
+const EXIT_INTERRUPTED: u8 = 130;
+
+#[derive(Clone, ValueEnum)]
+enum LogFormat {
+    Pretty,
+    Json,
+}
 
 #[derive(Parser)]
 #[command(
     version,
     about = "Send bulk texts via Apple Messages on macOS",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
     long_about = r#"
 Send bulk texts via Apple Messages on macOS, with optional personalization. A `.csv` path
-containing recipients and a `.txt` path containing the message text are required.
+containing recipients and a `.txt` path containing the message text are required (the
+latter may instead be given inline with `--text`).
 Optionally, the service (e.g., iMessage or SMS) and a placeholder for recipient names
 (replaced with a name every time it appears in the message) can also be provided.
+Additional placeholders may be bound to other CSV columns with `TOKEN=N`, where `N` is the
+1-based column index (e.g., `-p {name} -p {city}=3 -p {amount}=4`), or by header name
+with `TOKEN=NAME` if `--has-header` is provided (e.g., `-p {city}=City`). Whenever names
+are read, `{firstname}` is also replaced with the first word of each name. `{index}` is
+always replaced with each recipient's 1-based position in the order they are sent to (after
+`--shuffle`, `--start`, and the like), which is computed rather than read from the file.
 
-The CSV file of recipients should have no header and either one or two columns. If
+The CSV file of recipients should have no header (unless `--has-header` is provided; a
+stray header row is otherwise treated as a recipient) and either one or two columns (blank
+rows and rows starting with `#` are ignored, so the file may be annotated). If
 `--placeholder` (or `-p`) is provided, the first should contain recipient names and the
 second should contain phone numbers. For example:
 
@@ -39,18 +130,162 @@ containing phone numbers, like so:
 
     +1 (234) 567-8910
     314159265
-    [...]"#
+    [...]
+
+Email addresses registered as Apple IDs may be used in place of phone numbers (for
+iMessage only).
+
+Either layout may have one extra column after the phone numbers containing the path of a
+file (e.g., an image) to send as an attachment after the text. Rows with a blank cell in
+this column fall back to `--attachment` (or `-a`), if provided. Relative paths in this
+column are taken relative to the recipients file (and a leading `~` stands for your home
+directory), and rows whose attachment does not exist are skipped with a warning.
+
+If each recipient needs an entirely different message, `--message-column` names a CSV
+column holding the full message for each row (placeholders are still substituted). Rows
+with a blank cell in this column fall back to the shared message.
+
+To send different groups of recipients different messages, `--segment-column` names a CSV
+column holding each row's segment (e.g., `vip` or `new`), and each `--template vip=vip.txt`
+gives the message for one segment. Rows in any other segment (or none) get the shared
+message.
+
+Recipients may also be given as an Excel workbook (`.xlsx`), in which case the first sheet
+is read following the same conventions as the CSV file.
+
+Recipients may instead be given as a `.json` file containing an array of objects, each with
+a `number` and optionally a `name` and `attachment`. Placeholders are bound to any other
+fields by name (e.g., `-p {city}=city`), without the need for `--has-header`:
+
+    [{"name": "Rt. Hon. John A. Stymers", "number": "314159265", "city": "Ottawa"}]
+
+Contacts exported as vCards (`.vcf`) may be used too, sending to the first phone number
+(`TEL`) on each card, or to all of them with `--all-numbers`. The name placeholder is bound
+to the card's name (`FN`), and others to any property by name (e.g., `-p {company}=ORG`).
+
+Alternatively, `--group` (or `-g`) sends the message (unpersonalized) once to an existing
+group chat, identified by its name or ID, in which case no recipients are needed.
+
+Logs show each send at the info level by default. Pass `--quiet` (or `-q`) to log only
+warnings and errors, `-v` to add debug output, or `-vv` for everything (trace level). Without
+these flags, `RUST_LOG` may instead be set to a level (e.g., `RUST_LOG=debug`).
+
+The exit code is 0 if every attempted send succeeded (skipped rows do not count as
+failures), 1 on any other error, 2 on invalid usage, 3 if any send failed, 4 if the file
+contained no valid recipients (or none were left after filtering), and 130 if the run was
+interrupted with Ctrl-C."#
 )]
+struct Cli {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
+    // Without a subcommand, the options are those of `send`
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Send the message to every recipient (the default if no subcommand is given)
+    Send(Args),
+    /// Check the recipients and message, then exit without sending (same as --check)
+    Validate(Args),
+    /// Log each fully-rendered message instead of sending it (same as --dry-run)
+    Preview(Args),
+    /// Print a completion script for the given shell to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Check that this Mac is set up to send messages, with hints for fixing any problems
+    Doctor,
+}
+
+impl Cli {
+    fn into_args(self) -> Args {
+        match self.mode {
+            None => self.args,
+            Some(Mode::Send(args)) => args,
+            Some(Mode::Validate(args)) => Args {
+                check: true,
+                ..args
+            },
+            Some(Mode::Preview(args)) => Args {
+                dry_run: true,
+                ..args
+            },
+            Some(Mode::Completions { .. } | Mode::Doctor) => {
+                unreachable!("completions and diagnostics are handled before any options are used")
+            }
+        }
+    }
+}
+
+#[derive(clap::Args)]
+#[command(group(ArgGroup::new("body").required(true).args(["message", "text"])))]
+#[command(group(ArgGroup::new("seeded").multiple(true).args(["shuffle", "split"])))]
 struct Args {
     #[arg(
         short,
         long,
-        help = "Path to `.csv` file with recipients' numbers and (if applicable) names"
+        required_unless_present = "group",
+        help = "Path to `.csv` (or `.csv.gz`, `.xlsx`, `.json`, or `.vcf`) file with recipients' \
+                numbers (or Apple ID emails) and (if applicable) names, or - to read CSV from stdin"
+    )]
+    recipients: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        help = "Path to `.txt` file with the message to send (repeat to send each recipient one \
+                of several at random)"
+    )]
+    message: Vec<String>,
+
+    #[arg(
+        long,
+        requires = "message",
+        conflicts_with = "group",
+        value_parser = parse_split,
+        help = "(Optional) weights by which to split recipients between the messages, in the \
+                order given (e.g., 70/30), choosing by number so that re-runs are stable"
+    )]
+    split: Option<Split>,
+
+    #[arg(
+        long,
+        value_name = "SEGMENT=PATH",
+        requires = "segment_column",
+        conflicts_with = "group",
+        value_parser = parse_segment_template,
+        help = "(Optional) path to `.txt` file with the message to send recipients in SEGMENT of \
+                --segment-column instead (repeatable; others get the usual message)"
+    )]
+    template: Vec<SegmentTemplate>,
+
+    #[arg(long, help = "Message to send, given inline instead of via --message")]
+    text: Option<String>,
+
+    #[arg(
+        long,
+        help = "(Optional) text to append on a new line to every message (e.g., \"Reply STOP to \
+                unsubscribe\"), unless the message already ends with it"
+    )]
+    footer: Option<String>,
+
+    #[arg(
+        long,
+        help = "Send {A|B|C} groups in the message as is, rather than picking one option at random \
+                for each recipient"
     )]
-    recipients: String,
+    no_spintax: bool,
 
-    #[arg(short, long, help = "Path to `.txt` file with the message to send")]
-    message: String,
+    #[arg(
+        long,
+        help = "Substitute environment variables written as ${NAME} in the message (e.g., \
+                ${COMPANY}) once, before any placeholders"
+    )]
+    expand_env: bool,
 
     #[arg(
         short,
@@ -60,248 +295,2534 @@ struct Args {
     )]
     service: String,
 
+    #[arg(
+        long,
+        help = "(Optional) service to fall back to for recipients not registered with --service \
+                (e.g., SMS)"
+    )]
+    fallback_service: Option<String>,
+
     #[arg(
         short,
         long,
-        help = "(Optional) placeholder to be replaced with recipient name (e.g., {name})"
+        help = "(Optional) placeholder to be replaced with recipient name (e.g., {name}), or with \
+                CSV column N if given as TOKEN=N (e.g., {city}=3); may be repeated",
+        value_parser = parse_placeholder
     )]
-    placeholder: Option<String>,
-}
+    placeholder: Vec<Placeholder>,
 
-struct Recipient {
-    name: Option<String>,
-    number: String,
-}
+    #[arg(
+        long,
+        help = "(Optional) text to substitute for a placeholder whose value is blank for a \
+                recipient (e.g., \"there\" to send \"Hi there\" to recipients without a name)"
+    )]
+    placeholder_default: Option<String>,
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    #[arg(
+        long,
+        help = "Tidy up names before substituting them, collapsing extra spaces and title-casing \
+                each word (e.g., JOHN  SMITH becomes John Smith)"
+    )]
+    normalize_names: bool,
 
-    let args = Args::parse();
+    #[arg(
+        short,
+        long,
+        help = "Delay between consecutive messages (e.g., 500ms, 2s, or 1m)",
+        value_parser = parse_duration,
+        allow_hyphen_values = true,
+        default_value = DEFAULT_DELAY
+    )]
+    delay: Duration,
 
-    validate_file_path(&args.recipients, "csv")?;
-    validate_file_path(&args.message, "txt")?;
-    validate_service(&args.service)?;
+    #[arg(
+        long,
+        conflicts_with = "delay",
+        help = "(Optional) maximum number of messages to send per minute, as an alternative to \
+                --delay (e.g., 30 for a 2s delay)",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    rate: Option<u32>,
 
-    let has_names = args.placeholder.is_some();
-    let recipients = load_recipients(&args.recipients, has_names)?;
-    let template = read_message(&args.message)?;
+    #[arg(
+        long,
+        requires = "batch_pause",
+        help = "(Optional) number of messages to send before each --batch-pause",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    batch_size: Option<u32>,
 
-    send_messages(&recipients, &template, &args.placeholder, &args.service);
+    #[arg(
+        long,
+        requires = "batch_size",
+        help = "(Optional) pause after every --batch-size messages, in place of --delay \
+                (e.g., 5m)",
+        value_parser = parse_duration
+    )]
+    batch_pause: Option<Duration>,
 
-    Ok(())
-}
+    #[arg(
+        long,
+        help = "Number of messages to send in parallel (still subject to --delay overall)",
+        value_parser = clap::value_parser!(u32).range(1..),
+        default_value_t = DEFAULT_CONCURRENCY
+    )]
+    concurrency: u32,
 
-fn validate_file_path(path: &str, extension: &str) -> Result<()> {
-    if path.is_empty() {
-        bail!("Path must not be empty");
-    }
+    #[arg(
+        long,
+        help = "Randomize each delay by up to this percentage either way (e.g., 20 for ±20%)",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        default_value_t = 0
+    )]
+    jitter: u8,
 
-    let path_obj = Path::new(path);
+    #[arg(
+        long,
+        help = "Lengthen the delay after each failed send (a sign of throttling), up to \
+                --max-delay, and shorten it again gradually after successful ones"
+    )]
+    adaptive: bool,
 
-    if !path_obj.exists() {
-        bail!("Path {} does not exist", path);
-    }
+    #[arg(
+        long,
+        requires = "adaptive",
+        value_parser = parse_duration,
+        default_value = DEFAULT_MAX_DELAY,
+        help = "Longest delay --adaptive may lengthen the delay to"
+    )]
+    max_delay: Duration,
 
-    if !path_obj.is_file() {
-        bail!("{} exists but is not a file", path);
-    }
+    #[arg(
+        long,
+        help = "Log each fully-rendered message instead of sending it (no delay between messages)"
+    )]
+    dry_run: bool,
 
-    if path_obj.extension().and_then(|ext| ext.to_str()) != Some(extension) {
-        bail!("File {} does not end with .{}", path, extension);
-    }
+    #[arg(
+        long,
+        help = "(Optional) file to which a dry run also writes each recipient's rendered message \
+                and service for review (as JSON if it ends in .json, or else as text)"
+    )]
+    dry_run_out: Option<String>,
 
-    Ok(())
-}
+    #[arg(
+        long,
+        help = "Log the full AppleScript run for each message (along with other debug output) \
+                before running it, to help diagnose failed sends"
+    )]
+    print_script: bool,
 
-fn validate_service(service: &str) -> Result<()> {
-    if service.is_empty() {
-        bail!("Service name must not be empty");
-    }
+    #[arg(
+        long,
+        help = "(Optional) osascript binary (or wrapper) to run AppleScript with, instead of the \
+                one on PATH [env: AMSG_OSASCRIPT]"
+    )]
+    osascript_path: Option<String>,
 
-    if !service.chars().all(|c| c.is_ascii_alphabetic()) {
-        bail!(
-            "Invalid (non-alphabetic) characters in service name: {}",
-            service
-        );
-    }
+    #[arg(
+        long,
+        conflicts_with = "group",
+        help = "(Optional) file containing an AppleScript to send each message with instead of \
+                the built-in one, in which {{service}}, {{number}}, and {{message}} (and \
+                optionally {{attachment}}) are filled in"
+    )]
+    script_template: Option<String>,
 
-    Ok(())
-}
+    #[arg(
+        long,
+        help = "Quit Messages once all messages have been sent (e.g., on a dedicated Mac)"
+    )]
+    quit_after: bool,
 
-fn load_recipients(path: &str, has_names: bool) -> Result<Vec<Recipient>> {
-    Ok(read_recipients(path, has_names)?
-        .into_iter()
-        .filter_map(|r| match process_number(&r.number) {
-            Ok(processed_number) => Some(Recipient {
-                name: r.name,
-                number: processed_number,
-            }),
-            Err(e) => {
-                if let Some(name) = r.name {
-                    warn!("Skipping recipient {} due to invalid number: {}", name, e);
-                } else {
-                    warn!("Skipping recipient due to invalid number: {}", e);
-                }
-                None
-            }
-        })
-        .collect())
-}
+    #[arg(
+        long,
+        conflicts_with = "script_template",
+        help = "Send without bringing Messages to the front before each message, so that it \
+                does not steal focus while you work"
+    )]
+    no_activate: bool,
 
-fn read_recipients(path: &str, has_names: bool) -> Result<Vec<Recipient>> {
-    let mut rdr = ReaderBuilder::new()
-        .has_headers(false)
-        .from_path(path)
-        .context(format!("Failed to read CSV from {}", path))?;
+    #[arg(
+        long,
+        value_parser = parse_datetime,
+        help = "(Optional) RFC 3339 datetime (e.g., 2024-06-01T09:00:00-04:00) to wait until \
+                before sending"
+    )]
+    at: Option<DateTime<Local>>,
 
-    let mut recipients = Vec::new();
+    #[arg(
+        short,
+        long,
+        help = "Send without asking for confirmation first (e.g., for scripted use)"
+    )]
+    yes: bool,
 
-    for result in rdr.records() {
-        let record = result.context("Failed to read CSV record")?;
+    #[arg(
+        long,
+        conflicts_with = "yes",
+        help = "(Optional) number (or Apple ID email) to send the first message to as a test \
+                before the confirmation prompt, with any name placeholder filled in as \"Test\" \
+                (not with --yes, as the prompt is what gives the chance to check it)"
+    )]
+    test_number: Option<String>,
 
-        let (name, number) = if has_names {
-            (
-                Some(
-                    record
-                        .get(0)
-                        .context("Failed to get name from CSV record")?
-                        .trim()
-                        .to_string(),
-                ),
-                record
-                    .get(1)
-                    .context("Failed to get number from CSV record")?
-                    .trim()
-                    .to_string(),
-            )
-        } else {
-            (
-                None,
-                record
-                    .get(0)
-                    .context("Failed to get number from CSV record")?
-                    .trim()
-                    .to_string(),
-            )
-        };
+    #[arg(
+        long,
+        help = "Maximum number of times to retry a failed send",
+        default_value_t = DEFAULT_MAX_RETRIES
+    )]
+    max_retries: u32,
 
-        recipients.push(Recipient { name, number });
-    }
+    #[arg(
+        long,
+        help = "Wait before the first retry, doubled on each subsequent attempt (e.g., 500ms or 2s)",
+        value_parser = parse_duration,
+        allow_hyphen_values = true,
+        default_value = DEFAULT_RETRY_BASE_DELAY
+    )]
+    retry_base_delay: Duration,
 
-    Ok(recipients)
-}
+    #[arg(
+        long,
+        help = "Time to wait for a send before killing osascript and treating it as failed (and \
+                not retrying it, in case it went through)",
+        value_parser = parse_duration,
+        default_value = DEFAULT_SEND_TIMEOUT
+    )]
+    send_timeout: Duration,
 
-fn process_number(number: &str) -> Result<String> {
-    let number = number.trim();
+    #[arg(
+        long,
+        help = "(Optional) path to `.csv` file to write with each recipient's send status"
+    )]
+    report: Option<String>,
 
-    let (has_plus, stem) = if let Some(stripped) = number.strip_prefix('+') {
-        (true, stripped)
-    } else {
-        (false, number)
-    };
+    #[arg(
+        long,
+        help = "Write a JSON object to stdout for each recipient as they are sent to (or \
+                skipped), one per line, sending logs to stderr instead"
+    )]
+    events: bool,
 
-    if !stem
-        .chars()
-        .all(|c| c.is_ascii_digit() || c == ' ' || c == '-' || c == '(' || c == ')')
-    {
-        bail!("Invalid characters in phone number: {}", number);
-    }
+    #[cfg(feature = "webhook")]
+    #[arg(
+        long,
+        help = "(Optional) URL to POST a JSON summary of the run to once it finishes (with the \
+                counts, duration, and failed recipients)"
+    )]
+    webhook: Option<String>,
 
-    let digits = stem
-        .chars()
-        .filter(|c| c.is_ascii_digit())
-        .collect::<String>();
+    #[arg(
+        long,
+        help = "(Optional) path to `.csv` file to write with the recipients whose messages failed \
+                to send, in the same format as --recipients (for retrying them)"
+    )]
+    failures: Option<String>,
 
-    let len = digits.len();
+    #[arg(
+        short,
+        long,
+        help = "(Optional) path to a file to attach for recipients without their own attachment"
+    )]
+    attachment: Option<String>,
 
-    if len < MIN_NUMBER_LENGTH {
-        bail!("Phone number {} is too short", number);
-    } else if len > MAX_NUMBER_LENGTH {
-        bail!("Phone number {} is too long", number);
-    }
+    #[arg(
+        short,
+        long,
+        help = "(Optional) ISO 3166-1 alpha-2 code (e.g., US) for normalizing numbers to E.164 \
+                (treating a leading 00 like +, as in 0044 for the UK)",
+        value_parser = parse_country
+    )]
+    country: Option<country::Id>,
 
-    let number = if has_plus {
-        format!("+{}", digits)
-    } else {
-        digits
-    };
+    #[arg(
+        long,
+        help = "(Optional) only send to numbers with this area code (e.g., 415), compared \
+                without the country code if normalized; may be repeated",
+        value_parser = parse_area_code
+    )]
+    area_code: Vec<String>,
 
-    Ok(number)
-}
+    #[arg(
+        long,
+        help = "(Optional) path to a file of numbers (one per line) never to send to, e.g. those \
+                that have opted out"
+    )]
+    blocklist: Option<String>,
 
-fn read_message(path: &str) -> Result<String> {
-    fs::read_to_string(path).context(format!("Failed to read message from {}", path))
-}
+    #[arg(
+        long,
+        help = "Minimum number of digits in a valid phone number (e.g., lower for short codes)",
+        default_value_t = MIN_NUMBER_LENGTH
+    )]
+    min_digits: usize,
 
-fn send_messages(
-    recipients: &[Recipient],
-    template: &str,
-    placeholder: &Option<String>,
-    service: &str,
-) {
-    for recipient in recipients {
-        let message = if let (Some(name), Some(placeholder)) = (&recipient.name, placeholder) {
-            template.replace(placeholder, name)
-        } else {
-            template.to_string()
-        };
+    #[arg(
+        long,
+        help = "Maximum number of digits in a valid phone number",
+        default_value_t = MAX_NUMBER_LENGTH
+    )]
+    max_digits: usize,
 
-        if let Err(e) = send_message(&message, &recipient.number, service) {
-            if let Some(name) = &recipient.name {
-                error!(
-                    "Failed to send message to {} ({}): {}",
-                    name, recipient.number, e
-                );
-            } else {
-                error!("Failed to send message to {}: {}", recipient.number, e);
-            }
-        } else if let Some(name) = &recipient.name {
-            info!("Message sent to {} ({})", name, recipient.number);
-        } else {
-            info!("Message sent to {}", recipient.number);
-        }
+    #[arg(
+        long,
+        help = "Delimiter separating columns in the recipients CSV (e.g., ; or \\t for tabs)",
+        value_parser = parse_delimiter,
+        default_value = ","
+    )]
+    delimiter: u8,
 
-        thread::sleep(DELAY);
-    }
-}
+    #[arg(
+        long,
+        help = "Text encoding of the recipients CSV or JSON (e.g., utf-8 or latin1); Excel \
+                workbooks specify their own",
+        value_parser = parse_encoding,
+        default_value = "utf-8"
+    )]
+    encoding: &'static Encoding,
 
-fn send_message(message: &str, number: &str, service: &str) -> Result<()> {
-    let apple_script = format!(
-        r#"
-        tell application "Messages"
-            activate
-            set targetService to 1st service whose service type = {service}
-            set targetBuddy to buddy "{number}" of targetService
-            send "{message}" to targetBuddy
-        end tell
-        "#,
-        service = service,
-        number = number,
-        message = escape_applescript_string(message)
-    );
+    #[arg(
+        long,
+        help = "Treat the first row of the recipients CSV as a header, allowing placeholders to \
+                refer to columns by name (e.g., {city}=City)"
+    )]
+    has_header: bool,
 
-    let output = Command::new("osascript")
-        .arg("-e")
-        .arg(apple_script)
-        .output()
-        .context("Failed to execute AppleScript")?;
+    #[arg(
+        long,
+        help = "Send to every phone number on each vCard (.vcf) recipient, rather than just the \
+                first"
+    )]
+    all_numbers: bool,
 
-    if !output.status.success() {
-        bail!(
-            "AppleScript execution failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
+    #[arg(
+        long,
+        value_parser = parse_column,
+        help = "(Optional) CSV column (1-based index or header name) holding each recipient's own \
+                message, overriding the shared one unless blank"
+    )]
+    message_column: Option<Column>,
 
-    Ok(())
-}
+    #[arg(
+        long,
+        value_parser = parse_column,
+        help = "(Optional) CSV column (1-based index or header name) holding the service to send \
+                to each recipient with, overriding --service unless blank"
+    )]
+    service_column: Option<Column>,
+
+    #[arg(
+        long,
+        value_parser = parse_column,
+        help = "(Optional) CSV column (1-based index or header name) holding how long to wait \
+                after sending to each recipient (e.g., 30s), overriding --delay unless blank"
+    )]
+    delay_column: Option<Column>,
+
+    #[arg(
+        long,
+        requires = "template",
+        value_parser = parse_column,
+        help = "(Optional) CSV column (1-based index or header name) holding the segment each \
+                recipient belongs to (e.g., vip), which picks their --template"
+    )]
+    segment_column: Option<Column>,
+
+    #[arg(
+        long,
+        help = "Format of log output (JSON is newline-delimited, for log aggregators)",
+        value_enum,
+        default_value_t = LogFormat::Pretty
+    )]
+    log_format: LogFormat,
+
+    #[arg(
+        short,
+        long,
+        action = ArgAction::Count,
+        conflicts_with = "quiet",
+        help = "Log more detail: debug output with -v, or everything with -vv (overriding RUST_LOG)"
+    )]
+    verbose: u8,
+
+    #[arg(
+        short,
+        long,
+        help = "Log only warnings and errors (overriding RUST_LOG)"
+    )]
+    quiet: bool,
 
-fn escape_applescript_string(message: &str) -> String {
-    // Escape backslashes first to avoid double-escaping later replacements
-    message
-        .replace('\\', "\\\\")
-        .replace('"', "\\\"")
-        .replace('\n', "\\n")
-        .replace('\t', "\\t")
-        .replace('\r', "\\r")
+    #[arg(long, help = "Hide the progress bar (e.g., for non-interactive use)")]
+    no_progress: bool,
+
+    #[arg(
+        long,
+        help = "Log without colors, which are otherwise used when logging to a terminal (unless \
+                NO_COLOR is set)"
+    )]
+    no_color: bool,
+
+    #[arg(
+        long,
+        help = "Mask all but the last four digits of numbers in logs (e.g., when screen-sharing)"
+    )]
+    mask_numbers: bool,
+
+    #[arg(
+        long,
+        requires = "report",
+        help = "Mask all but the last four digits of numbers in the --report CSV"
+    )]
+    mask_report_numbers: bool,
+
+    #[arg(
+        long,
+        requires = "report",
+        help = "Add a latency_ms column to the --report CSV with how long sending to each \
+                recipient took"
+    )]
+    report_latency: bool,
+
+    #[arg(
+        long,
+        help = "Send to every row, even if its (normalized) number appeared in an earlier one"
+    )]
+    allow_duplicates: bool,
+
+    #[arg(
+        long,
+        help = "(Optional) maximum number of recipients to send to, taken from the top of the list"
+    )]
+    limit: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Send to recipients in a random order (before --start, --end, and --limit are \
+                applied), which requires holding them all in memory"
+    )]
+    shuffle: bool,
+
+    #[arg(
+        long,
+        requires = "seeded",
+        help = "(Optional) seed for --shuffle (and --split), to reproduce the same order (and \
+                choice of messages) across runs"
+    )]
+    seed: Option<u64>,
+
+    #[arg(
+        long,
+        help = "(Optional) 1-based position of the first valid recipient to send to (e.g., to \
+                redo part of a run)",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    start: Option<u64>,
+
+    #[arg(
+        long,
+        help = "(Optional) 1-based position of the last valid recipient to send to (inclusive)",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    end: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Validate the recipients and message, listing every problem found, then exit \
+                without sending (failing if there were any)"
+    )]
+    check: bool,
+
+    #[arg(
+        long,
+        help = "Abort before sending anything if any row is invalid (or any --expand-env variable \
+                is unset), instead of skipping it"
+    )]
+    strict: bool,
+
+    #[arg(
+        long,
+        help = "Convert letters in vanity numbers (e.g., 1-800-FLOWERS) to the digits on the \
+                same phone keypad keys instead of rejecting them"
+    )]
+    vanity: bool,
+
+    #[arg(
+        long,
+        help = "(Optional) path to `.json` checkpoint recording numbers sent to, so that an \
+                interrupted run can be resumed without duplicate sends"
+    )]
+    resume: Option<String>,
+
+    #[cfg(feature = "history")]
+    #[arg(
+        long,
+        help = "(Optional) SQLite database recording each send across runs (created if it does \
+                not exist), with numbers stored hashed"
+    )]
+    history: Option<String>,
+
+    #[cfg(feature = "history")]
+    #[arg(
+        long,
+        requires = "history",
+        help = "(Optional) name of the campaign to record sends under in the --history database"
+    )]
+    campaign: Option<String>,
+
+    #[cfg(feature = "history")]
+    #[arg(
+        long,
+        requires = "history",
+        value_parser = parse_duration,
+        help = "(Optional) skip recipients sent to within this long (e.g., 30days) in an earlier \
+                run recorded in the --history database"
+    )]
+    dedupe_window: Option<Duration>,
+
+    #[arg(
+        short,
+        long,
+        conflicts_with = "recipients",
+        help = "(Optional) name or ID of an existing group chat to send the message to once, \
+                instead of to individual recipients"
+    )]
+    group: Option<String>,
+
+    #[arg(
+        long,
+        help = "(Optional) path to `.toml` file with default values for any of these options \
+                (defaults to amsg-batch.toml in the current directory, if present)"
+    )]
+    config: Option<String>,
+}
+
+fn main() -> Result<ExitCode> {
+    let cli = parse_args()?;
+
+    if let Some(Mode::Completions { shell }) = cli.mode {
+        clap_complete::generate(
+            shell,
+            &mut Cli::command(),
+            env!("CARGO_PKG_NAME"),
+            &mut io::stdout(),
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Mode::Doctor) = cli.mode {
+        return Ok(doctor());
+    }
+
+    let args = cli.into_args();
+
+    // Dry runs and checks never reach `osascript`, so they remain useful on other platforms
+    if !cfg!(target_os = "macos") && !args.dry_run && !args.check {
+        bail!(
+            "amsg-batch only works on macOS (requires the Messages app and osascript); \
+             use --dry-run or --check to preview a run elsewhere"
+        );
+    }
+
+    set_number_masking(args.mask_numbers);
+    set_activation(!args.no_activate);
+    init_logging(&args);
+
+    if let Some(path) = osascript_override(&args) {
+        set_osascript_path(path);
+    }
+
+    for path in args
+        .message
+        .iter()
+        .chain(args.template.iter().map(|template| &template.path))
+    {
+        validate_file_path(path, &["txt"])?;
+    }
+    validate_service(&args.service)?;
+
+    if let Some(fallback_service) = &args.fallback_service {
+        validate_service(fallback_service)?;
+    }
+
+    let script_template = args
+        .script_template
+        .as_deref()
+        .map(ScriptTemplate::load)
+        .transpose()?;
+
+    if let Some(template) = &script_template
+        && args.attachment.is_some()
+        && !template.has_attachment()
+    {
+        bail!(
+            "--attachment requires the script template to contain {}",
+            ScriptTemplate::ATTACHMENT_PLACEHOLDER
+        );
+    }
+
+    if args.dry_run_out.is_some() && !args.dry_run {
+        bail!("--dry-run-out requires --dry-run (or the preview subcommand)");
+    }
+
+    // Failed rows are written back out as CSV records, which other formats do not have
+    if args.failures.is_some()
+        && args
+            .recipients
+            .as_deref()
+            .is_some_and(|path| RecipientFormat::from_path(path) != RecipientFormat::Csv)
+    {
+        bail!("--failures is only supported for CSV (and Excel) recipients");
+    }
+
+    if args.min_digits > args.max_digits {
+        bail!(
+            "--min-digits ({}) must not exceed --max-digits ({})",
+            args.min_digits,
+            args.max_digits
+        );
+    }
+
+    if let Some(split) = &args.split
+        && split.weights().len() != args.message.len()
+    {
+        bail!(
+            "--split gives {} weight(s), but {} message(s) were given",
+            split.weights().len(),
+            args.message.len()
+        );
+    }
+
+    if let Some(at) = args.at
+        && at <= Local::now()
+    {
+        bail!("Scheduled send time {} is in the past", at.to_rfc3339());
+    }
+
+    if let Some(group) = &args.group {
+        let templates = message_templates(&args)?;
+        let template = &templates[rand::random_range(..templates.len())];
+        let message = render_message_with(
+            template,
+            &HashMap::new(),
+            |template| spin_message(template, &mut rand::rng()),
+            &args,
+        );
+
+        if let Some(at) = args.at {
+            wait_until(at, args.dry_run)?;
+        }
+
+        if !args.dry_run {
+            ensure_messages_running(args.send_timeout)?;
+        }
+
+        let code = send_to_group(group, &message, &args)?;
+        quit_messages_after(&args);
+
+        return Ok(code);
+    }
+
+    // A mistyped path is caught before Messages is launched and its services are queried
+    let recipients_path = args
+        .recipients
+        .as_deref()
+        .context("Recipients must be provided unless sending to a group")?;
+
+    if recipients_path != STDIN_PATH {
+        validate_file_path(recipients_path, RECIPIENT_EXTENSIONS)?;
+    }
+
+    // A misspelt service would otherwise fail every send with an obscure AppleScript error
+    let check_services = !args.dry_run && !args.check;
+
+    if check_services {
+        ensure_messages_running(args.send_timeout)?;
+
+        for service in iter::once(&args.service).chain(&args.fallback_service) {
+            check_service_available(service, args.send_timeout)?;
+        }
+    }
+
+    let csv_options = CsvOptions {
+        format: RecipientFormat::from_path(recipients_path),
+        delimiter: args.delimiter,
+        has_header: args.has_header,
+        message_column: args.message_column.clone(),
+        service_column: args.service_column.clone(),
+        delay_column: args.delay_column.clone(),
+        segment_column: args.segment_column.clone(),
+        // Workbooks are converted to UTF-8 CSV whatever encoding their text is stored in
+        encoding: if Path::new(recipients_path)
+            .extension()
+            .is_some_and(|ext| ext == "xlsx")
+        {
+            UTF_8
+        } else {
+            args.encoding
+        },
+        normalize_names: args.normalize_names,
+        all_numbers: args.all_numbers,
+    };
+    let number_options = NumberOptions {
+        country: args.country,
+        min_digits: args.min_digits,
+        max_digits: args.max_digits,
+        // Checks report every invalid row rather than stopping at the first
+        strict: args.strict && !args.check,
+        vanity: args.vanity,
+    };
+    let templates = message_templates(&args)?;
+    let segments = segment_templates(&args)?;
+
+    // With several templates, each recipient is instead given one as their own message
+    let template = templates[0].clone();
+
+    // Stdin cannot be read twice, so unlike files it is held in memory for the second pass
+    let stdin = if recipients_path == STDIN_PATH {
+        let mut bytes = Vec::new();
+        io::stdin()
+            .read_to_end(&mut bytes)
+            .context("Failed to read recipients from stdin")?;
+        Some(bytes)
+    } else {
+        None
+    };
+    let open = || -> Result<Box<dyn Read + Send + '_>> {
+        match &stdin {
+            Some(bytes) => Ok(Box::new(bytes.as_slice())),
+            None => Ok(open_recipients(recipients_path, &csv_options)?),
+        }
+    };
+
+    // Otherwise held in memory, shuffled recipients are the only ones not reread from the file
+    let stamp = (stdin.is_none() && !args.shuffle)
+        .then(|| file_stamp(recipients_path))
+        .transpose()?;
+    let mut stream = stream_recipients(open()?, &csv_options, &args.placeholder, &number_options)?;
+    let header = stream.header.take();
+
+    if args.check {
+        let attachment_base = attachment_base(&args);
+        let mut checks = MessageChecks::default();
+        let mut problems = ValidationErrors::default();
+        let mut rows = 0;
+
+        // Every problem is gathered before reporting, so that all can be fixed in one pass
+        for loaded in stream.quiet() {
+            rows += 1;
+
+            let mut recipient = match loaded {
+                Ok(LoadedRecipient::Valid(recipient)) => recipient,
+                Ok(LoadedRecipient::Invalid(s)) => {
+                    problems.push(Some(s.recipient.line), s.reason);
+                    continue;
+                }
+                Err(e) => {
+                    problems.push_error(&e);
+
+                    // Malformed records can be skipped over, but not a source that failed
+                    if e.is_io() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            if let Err(e) =
+                resolve_recipient_attachment(&mut recipient, None, attachment_base.as_deref())
+            {
+                problems.push(Some(recipient.line), e.to_string());
+            }
+
+            if let Some(default) = &args.placeholder_default {
+                recipient.fill_blank_fields(default);
+            }
+
+            // Templates picked at random are not known until sending, so each one counts
+            if message_options(&recipient, &templates, &segments, &args)
+                .into_iter()
+                .any(|template| may_render_empty(&recipient, template, &args))
+            {
+                problems.push(Some(recipient.line), SkipReason::EmptyMessage.as_str());
+            }
+
+            checks.observe_tokens(&recipient);
+        }
+
+        let messages = templates
+            .iter()
+            .chain(segments.values().map(|(_, template)| template));
+
+        for token in checks.unknown_tokens(messages, &args) {
+            problems.push(
+                None,
+                format!("Message contains {} but no placeholder provides it", token),
+            );
+        }
+
+        check_recipients(rows, &problems)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let default_attachment = args
+        .attachment
+        .as_deref()
+        .map(resolve_attachment)
+        .transpose()?;
+
+    // Shuffling needs every recipient at once, so only then are they all held in memory.
+    // Shuffling first also means that --start, --end, and --limit select from the shuffled order
+    let loaded: Box<dyn Iterator<Item = _>> = if args.shuffle {
+        let LoadedRecipients {
+            mut recipients,
+            skipped,
+            ..
+        } = stream.load()?;
+        shuffle_recipients(&mut recipients, args.seed);
+
+        Box::new(
+            skipped
+                .into_iter()
+                .map(LoadedRecipient::Invalid)
+                .chain(recipients.into_iter().map(LoadedRecipient::Valid))
+                .map(Ok),
+        )
+    } else {
+        Box::new(stream)
+    };
+
+    // Both passes must give each recipient the same template, so they share a seed for it
+    let seed = args.seed.unwrap_or_else(rand::random);
+
+    // The first pass only counts and checks the recipients to send to, keeping just the first
+    let mut selection = Selection::new(
+        &args,
+        &templates,
+        &segments,
+        &number_options,
+        default_attachment.clone(),
+        seed,
+    )?;
+    let mut checks = MessageChecks::default();
+    let mut skipped = Vec::new();
+    let mut shuffled = Vec::new();
+    let mut first = None;
+
+    for loaded in loaded {
+        match selection.select(loaded?)? {
+            Selected::Send(recipient) => {
+                if check_services && let Some(service) = &recipient.service {
+                    check_service_available(service, args.send_timeout).with_context(|| {
+                        format!("Invalid service for recipient on line {}", recipient.line)
+                    })?;
+                }
+
+                checks.observe(&recipient, &template, seed, &args);
+
+                if first.is_none() {
+                    first = Some(recipient.clone());
+                }
+
+                if args.shuffle {
+                    shuffled.push(recipient);
+                }
+            }
+            Selected::Skip(s) => skipped.push(s),
+            Selected::Drop => {}
+        }
+    }
+
+    if selection.loaded == 0 {
+        error!(
+            "No valid recipients found ({} row(s) skipped)",
+            skipped.len()
+        );
+        return Ok(ExitCode::from(EXIT_NO_RECIPIENTS));
+    }
+
+    selection.summarize()?;
+
+    let count = selection.selected();
+
+    // Otherwise a run whose every recipient was filtered out would seem to succeed
+    if count == 0 {
+        error!(
+            "No valid recipients after filtering ({} row(s) skipped)",
+            skipped.len()
+        );
+        return Ok(ExitCode::from(EXIT_NO_RECIPIENTS));
+    }
+
+    checks.warn(&templates, &args);
+    log_send_plan(count, &args);
+
+    // Whatever records the run are set up before the test message, the prompt, and any wait,
+    // so that a problem with them comes to light before the user has to answer or wait
+    let mut records = Records {
+        report: args
+            .report
+            .as_deref()
+            .map(|path| {
+                Report::create(
+                    path,
+                    args.mask_report_numbers,
+                    templates.len() > 1 || !segments.is_empty(),
+                    args.report_latency,
+                )
+            })
+            .transpose()?,
+        // Recording sends must not affect which recipients the second pass selects, so the
+        // checkpoint is loaded again rather than shared with the selection
+        checkpoint: args.resume.as_deref().map(Checkpoint::load).transpose()?,
+        #[cfg(feature = "history")]
+        history: args
+            .history
+            .as_deref()
+            .map(|path| History::open(path, args.campaign.as_deref()))
+            .transpose()?,
+        failures: args
+            .failures
+            .as_deref()
+            .map(|path| FailureLog::create(path, &csv_options, header.as_ref()))
+            .transpose()?,
+        events: args.events.then(|| EventStream::new(io::stdout())),
+        dry_run_out: args
+            .dry_run_out
+            .as_deref()
+            .map(DryRunOutput::create)
+            .transpose()?,
+    };
+
+    let sender = match script_template {
+        Some(template) => OsascriptSender::new(args.send_timeout).with_template(template),
+        None => OsascriptSender::new(args.send_timeout),
+    };
+
+    if let (Some(test_number), Some(first)) = (&args.test_number, &first) {
+        let test_number = if is_email(test_number) {
+            test_number.to_lowercase()
+        } else {
+            process_number(test_number, &number_options)?
+        };
+
+        send_test_message(&sender, first, &template, seed, &test_number, &args)?;
+    }
+
+    if !args.dry_run && !args.yes && !confirm_send(first.as_ref(), count, &template, seed, &args)? {
+        info!("Aborted without sending any messages");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(at) = args.at {
+        wait_until(at, args.dry_run)?;
+
+        // Messages may well have been quit in the meantime
+        if !args.dry_run {
+            ensure_messages_running(args.send_timeout)?;
+        }
+    }
+
+    // The second pass rereads the file, so one changed since the first (e.g., during the wait
+    // for --at) would have other recipients sent to than were counted and confirmed
+    if let Some(stamp) = stamp
+        && file_stamp(recipients_path)? != stamp
+    {
+        bail!(
+            "{} changed after its recipients were counted, so none were sent to (run again to \
+             send to the recipients it now has)",
+            recipients_path
+        );
+    }
+
+    records.record_skipped(&skipped)?;
+
+    // The second pass rereads the recipients, this time sending to them one at a time
+    let recipients: Box<dyn Iterator<Item = amsg_batch::Result<Recipient>> + Send + '_> = if args
+        .shuffle
+    {
+        Box::new(shuffled.into_iter().map(Ok))
+    } else {
+        let mut selection = Selection::new(
+            &args,
+            &templates,
+            &segments,
+            &number_options,
+            default_attachment,
+            seed,
+        )?
+        .quiet();
+        let stream =
+            stream_recipients(open()?, &csv_options, &args.placeholder, &number_options)?.quiet();
+
+        Box::new(
+            stream
+                .filter_map(
+                    move |loaded| match loaded.and_then(|l| selection.select(l)) {
+                        Ok(Selected::Send(recipient)) => Some(Ok(recipient)),
+                        Ok(_) => None,
+                        Err(e) => Some(Err(e)),
+                    },
+                )
+                .take(count),
+        )
+    };
+
+    let started = Instant::now();
+    let tally = send_messages(
+        &sender,
+        recipients,
+        count,
+        &template,
+        seed,
+        &args,
+        &mut records,
+    )?;
+
+    if let Some(output) = records.dry_run_out {
+        output.finish()?;
+    }
+
+    if let Some(events) = records.events {
+        events.finish(tally.sent, tally.failed, skipped.len())?;
+    }
+
+    let elapsed = humantime::format_duration(Duration::from_secs(started.elapsed().as_secs()));
+
+    if args.dry_run {
+        info!(
+            "[Dry run] {} would be sent, {} skipped in {}",
+            tally.sent,
+            skipped.len(),
+            elapsed
+        );
+    } else {
+        info!(
+            "{} sent, {} failed, {} skipped in {}",
+            tally.sent,
+            tally.failed,
+            skipped.len(),
+            elapsed
+        );
+
+        if let Some(latency) = SendLatency::from_samples(&tally.latencies) {
+            // Milliseconds are plenty precise for tuning the delay
+            let ms = |d: Duration| {
+                humantime::format_duration(Duration::from_millis(d.as_millis() as u64))
+            };
+            info!(
+                "Send latency: min {}, median {}, p95 {}, max {}",
+                ms(latency.min),
+                ms(latency.median),
+                ms(latency.p95),
+                ms(latency.max)
+            );
+        }
+    }
+
+    quit_messages_after(&args);
+
+    #[cfg(feature = "webhook")]
+    if let Some(url) = &args.webhook {
+        let summary = RunSummary {
+            sent: tally.sent,
+            failed: tally.failed,
+            skipped: skipped.len(),
+            duration_secs: started.elapsed().as_secs(),
+            dry_run: args.dry_run,
+            interrupted: INTERRUPTED.load(Ordering::SeqCst),
+            failures: tally.failures,
+        };
+
+        // The messages have already gone out, so a missed notification does not fail the run
+        match post_webhook(url, &summary, args.send_timeout) {
+            Ok(()) => info!(url = %url, "Webhook delivered"),
+            // Include the cause (e.g., a refused connection), which the context alone leaves out
+            Err(e) => warn!(
+                url = %url,
+                error = format!("{:#}", anyhow::Error::new(e)),
+                "Failed to deliver webhook"
+            ),
+        }
+    }
+
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Ok(ExitCode::from(EXIT_INTERRUPTED))
+    } else if tally.failed > 0 {
+        Ok(ExitCode::from(EXIT_SEND_FAILED))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+// Prints a checklist of what sending requires, returning failure if anything is missing
+fn doctor() -> ExitCode {
+    let timeout = humantime::parse_duration(DEFAULT_SEND_TIMEOUT).expect("valid default timeout");
+    let mut failed = 0;
+    let mut check = |name: &str, result: Result<String, String>, hint: &str| match result {
+        Ok(found) => println!("[ok]   {}: {}", name, found),
+        Err(problem) => {
+            failed += 1;
+            println!("[fail] {}: {}\n       {}", name, problem, hint);
+        }
+    };
+
+    check(
+        "macOS",
+        macos_version(),
+        "amsg-batch sends through the Messages app, so it only works on macOS",
+    );
+    let osascript = match env::var(OSASCRIPT_PATH_ENV) {
+        Ok(path) if !path.is_empty() => {
+            set_osascript_path(&path);
+            Path::new(&path)
+                .is_file()
+                .then(|| format!("found at {} (from {})", path, OSASCRIPT_PATH_ENV))
+                .ok_or_else(|| format!("{} (from {}) does not exist", path, OSASCRIPT_PATH_ENV))
+        }
+        _ => find_on_path("osascript"),
+    };
+    check(
+        "osascript",
+        osascript,
+        "osascript ships with macOS in /usr/bin; make sure that is on your PATH (or that \
+         AMSG_OSASCRIPT points to it)",
+    );
+    check(
+        "Messages",
+        [
+            "/System/Applications/Messages.app",
+            "/Applications/Messages.app",
+        ]
+        .into_iter()
+        .find(|path| Path::new(path).is_dir())
+        .map(|path| format!("installed at {}", path))
+        .ok_or_else(|| String::from("not installed")),
+        "Reinstall macOS or restore Messages from a backup, as it cannot be downloaded alone",
+    );
+    check(
+        "AppleScript",
+        check_apple_script(timeout)
+            .map(|()| String::from("osascript runs scripts"))
+            .map_err(|e| e.to_string()),
+        "Check that osascript runs from your terminal (e.g., osascript -e 'return 1')",
+    );
+
+    let services = available_services(timeout);
+    check(
+        "Automation",
+        match &services {
+            Ok(_) => Ok(String::from("allowed to control Messages")),
+            Err(e) => Err(e.to_string()),
+        },
+        "Allow your terminal to control Messages under System Settings > Privacy & Security > \
+         Automation (or run a send to be prompted)",
+    );
+    check(
+        "Accounts",
+        match &services {
+            Ok([]) => Err(String::from("no accounts set up in Messages")),
+            Ok(services) => Ok(format!("services available: {}", services.join(", "))),
+            Err(_) => Err(String::from(
+                "could not be listed without Automation access",
+            )),
+        },
+        "Sign in to iMessage in Messages > Settings, and turn on Text Message Forwarding on your \
+         iPhone to send SMS",
+    );
+
+    if failed > 0 {
+        println!("\n{} check(s) failed", failed);
+        ExitCode::FAILURE
+    } else {
+        println!("\nEverything looks ready to send");
+        ExitCode::SUCCESS
+    }
+}
+
+fn osascript_override(args: &Args) -> Option<String> {
+    args.osascript_path
+        .clone()
+        .or_else(|| env::var(OSASCRIPT_PATH_ENV).ok())
+        .filter(|path| !path.is_empty())
+}
+
+fn macos_version() -> Result<String, String> {
+    if !cfg!(target_os = "macos") {
+        return Err(format!("running on {}", env::consts::OS));
+    }
+
+    let output = process::Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .map_err(|e| format!("failed to run sw_vers: {}", e))?;
+
+    Ok(format!(
+        "version {}",
+        String::from_utf8_lossy(&output.stdout).trim()
+    ))
+}
+
+fn find_on_path(program: &str) -> Result<String, String> {
+    env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+        .map(|path| format!("found at {}", path.display()))
+        .ok_or_else(|| String::from("not found on PATH"))
+}
+
+// Parses the command line, filling in options it does not set from the config file (if any)
+fn parse_args() -> Result<Cli> {
+    Ok(Cli::parse_from(merge_config(env::args_os().collect())?))
+}
+
+// The command line with the options from the config file (if any) that it does not override
+// inserted, ready for the final parse
+fn merge_config(cli: Vec<OsString>) -> Result<Vec<OsString>> {
+    // Required options may come from the config file, so errors are left for the final parse
+    let matches = Cli::command().ignore_errors(true).get_matches_from(&cli);
+    let (subcommand, matches) = match matches.subcommand() {
+        Some((name, matches)) => (Some(name), matches),
+        None => (None, &matches),
+    };
+
+    // Every mode shares the same options, so they are looked up independently of the mode
+    let command = Args::augment_args(clap::Command::new(env!("CARGO_PKG_NAME")));
+
+    // Completion scripts and diagnostics do not depend on any options
+    if matches!(subcommand, Some("completions" | "doctor")) {
+        return Ok(cli);
+    }
+
+    let path = match matches.get_one::<String>("config") {
+        Some(path) => path.as_str(),
+        None if Path::new(DEFAULT_CONFIG_PATH).is_file() => DEFAULT_CONFIG_PATH,
+        None => return Ok(cli),
+    };
+
+    let config = fs::read_to_string(path).context(format!("Failed to read config {}", path))?;
+    let config: Table = config
+        .parse()
+        .context(format!("Failed to parse config {}", path))?;
+
+    let on_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    // Mutually exclusive options, whether declared on either one or via an exclusive group
+    let conflicts = |a: &Arg, b: &Arg| {
+        let declared = |a: &Arg, b: &Arg| {
+            command
+                .get_arg_conflicts_with(a)
+                .iter()
+                .any(|other| other.get_id() == b.get_id())
+        };
+        let grouped = command
+            .get_groups()
+            .filter(|&group| !group.clone().is_multiple())
+            .any(|group| {
+                group.get_args().any(|id| id == a.get_id())
+                    && group.get_args().any(|id| id == b.get_id())
+            });
+
+        declared(a, b) || declared(b, a) || grouped
+    };
+
+    // Options from the file go after the subcommand (if any), which must come first
+    let skip = if subcommand.is_some() { 2 } else { 1 };
+    let mut merged = cli.iter().take(skip).cloned().collect::<Vec<_>>();
+
+    for (key, value) in &config {
+        let id = key.replace('-', "_");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && arg.get_id() != "config")
+        else {
+            bail!("Unknown option {} in config {}", key, path);
+        };
+
+        // Options given on the command line override those (and their alternatives) in the file
+        let overridden = command
+            .get_arguments()
+            .filter(|other| on_cli(other.get_id().as_str()))
+            .any(|other| other.get_id() == arg.get_id() || conflicts(arg, other));
+
+        if overridden {
+            continue;
+        }
+
+        let flag = format!("--{}", arg.get_long().unwrap_or(&id));
+
+        let values = match value {
+            TomlValue::Boolean(true) => {
+                merged.push(flag.into());
+                continue;
+            }
+            TomlValue::Boolean(false) => continue,
+            // Counted flags (e.g., `verbose = 2`) are repeated rather than given a value
+            TomlValue::Integer(count) if matches!(arg.get_action(), ArgAction::Count) => {
+                for _ in 0..*count {
+                    merged.push(flag.clone().into());
+                }
+                continue;
+            }
+            TomlValue::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+
+        for value in values {
+            let value = match value {
+                TomlValue::String(value) => value.clone(),
+                TomlValue::Integer(_) | TomlValue::Float(_) | TomlValue::Datetime(_) => {
+                    value.to_string()
+                }
+                _ => bail!("Unsupported value for {} in config {}", key, path),
+            };
+
+            merged.push(format!("{}={}", flag, value).into());
+        }
+    }
+
+    merged.extend(cli.into_iter().skip(skip));
+
+    Ok(merged)
+}
+
+// Relative attachment paths are resolved against the directory of the recipients file (or the
+// current directory, for stdin)
+fn attachment_base(args: &Args) -> Option<PathBuf> {
+    args.recipients
+        .as_deref()
+        .filter(|&path| path != STDIN_PATH)
+        .and_then(|path| Path::new(path).parent())
+        .map(Path::to_path_buf)
+}
+
+// Reads each --template, keyed by its segment in lowercase (as segments are matched
+// case-insensitively) alongside its path
+fn segment_templates(args: &Args) -> Result<HashMap<String, (String, String)>> {
+    let mut segments = HashMap::new();
+
+    for template in &args.template {
+        let message = read_message(&template.path)?;
+
+        if message.trim().is_empty() {
+            bail!("Message {} is empty", template.path);
+        }
+
+        let message = expand_template_env(&message, &template.path, args)?;
+
+        if segments
+            .insert(
+                template.segment.to_lowercase(),
+                (template.path.clone(), message),
+            )
+            .is_some()
+        {
+            bail!(
+                "More than one --template given for segment {}",
+                template.segment
+            );
+        }
+    }
+
+    Ok(segments)
+}
+
+fn message_templates(args: &Args) -> Result<Vec<String>> {
+    if let Some(text) = &args.text {
+        if text.trim().is_empty() {
+            bail!("--text must not be empty");
+        }
+        return Ok(vec![expand_template_env(text, "--text", args)?]);
+    }
+
+    if args.message.is_empty() {
+        bail!("Either --message or --text must be provided");
+    }
+
+    let mut templates = Vec::with_capacity(args.message.len());
+
+    for path in &args.message {
+        let template = read_message(path)?;
+
+        if template.trim().is_empty() {
+            bail!("Message {} is empty", path);
+        }
+
+        templates.push(expand_template_env(&template, path, args)?);
+    }
+
+    Ok(templates)
+}
+
+// Substitutes environment variables (with --expand-env), warning about unset ones or, with
+// --strict, failing on them
+fn expand_template_env(template: &str, source: &str, args: &Args) -> Result<String> {
+    if !args.expand_env {
+        return Ok(template.to_string());
+    }
+
+    let (expanded, unset) = expand_env_vars(template, |name| env::var(name).ok());
+
+    if args.strict && !unset.is_empty() {
+        bail!(
+            "Environment variable(s) {} in {} are not set",
+            unset.join(", "),
+            source
+        );
+    }
+
+    for name in &unset {
+        warn!(
+            variable = %name,
+            "Environment variable {} in {} is not set, so ${{{}}} will be sent as is",
+            name,
+            source,
+            name
+        );
+    }
+
+    Ok(expanded)
+}
+
+// Renders a recipient's own message (if any) or else `template` for them, spinning any spintax
+// the same way each time within a run (as given by `seed`)
+fn render(recipient: &Recipient, template: &str, seed: u64, args: &Args) -> String {
+    render_message_with(
+        recipient.message.as_deref().unwrap_or(template),
+        &recipient.fields,
+        |template| spin_message_for(template, &recipient.number, seed),
+        args,
+    )
+}
+
+// Whether a recipient's message may be blank once rendered, whichever spintax options are
+// picked (unless a footer is appended to it regardless)
+fn may_render_empty(recipient: &Recipient, template: &str, args: &Args) -> bool {
+    let template = recipient.message.as_deref().unwrap_or(template);
+    let blank = if args.no_spintax {
+        render_message(template, &recipient.fields)
+            .trim()
+            .is_empty()
+    } else {
+        may_render_blank(template, &recipient.fields)
+    };
+
+    blank
+        && args
+            .footer
+            .as_deref()
+            .is_none_or(|footer| footer.trim().is_empty())
+}
+
+// The template for a recipient's segment, if `--template` gives it one (along with its path)
+fn segment_template<'a>(
+    recipient: &Recipient,
+    segments: &'a HashMap<String, (String, String)>,
+) -> Option<&'a (String, String)> {
+    recipient
+        .segment
+        .as_ref()
+        .and_then(|segment| segments.get(&segment.to_lowercase()))
+}
+
+// Which of several templates `--split` gives a recipient, or `None` if picked at random (albeit
+// consistently within a run)
+fn split_choice(recipient: &Recipient, args: &Args) -> Option<usize> {
+    args.split
+        .as_ref()
+        .map(|split| split.choose(&recipient.number, args.seed.unwrap_or_default()))
+}
+
+// The templates a recipient may be sent (any of them, if picked at random), though their own
+// message takes precedence over them all
+fn message_options<'a>(
+    recipient: &Recipient,
+    templates: &'a [String],
+    segments: &'a HashMap<String, (String, String)>,
+    args: &Args,
+) -> Vec<&'a str> {
+    if let Some((_, template)) = segment_template(recipient, segments) {
+        return vec![template];
+    }
+
+    match split_choice(recipient, args) {
+        Some(choice) => vec![&templates[choice]],
+        None => templates.iter().map(String::as_str).collect(),
+    }
+}
+
+// Expands spintax with `spin` (unless disabled) before substituting placeholders, so that
+// substituted values containing braces are left intact, then appends the footer (if any)
+fn render_message_with(
+    template: &str,
+    fields: &HashMap<String, String>,
+    spin: impl FnOnce(&str) -> String,
+    args: &Args,
+) -> String {
+    let message = if args.no_spintax {
+        render_message(template, fields)
+    } else {
+        render_message(&spin(template), fields)
+    };
+
+    match &args.footer {
+        Some(footer) => append_footer(message, footer),
+        None => message,
+    }
+}
+
+// What the warnings about a run's messages need to know, gathered one recipient at a time
+#[derive(Default)]
+struct MessageChecks {
+    // The tokens recipients' fields provide, and those their own messages contain
+    known: HashSet<String>,
+    tokens: Vec<String>,
+    long_messages: usize,
+    max_segments: usize,
+}
+
+impl MessageChecks {
+    fn observe(&mut self, recipient: &Recipient, template: &str, seed: u64, args: &Args) {
+        self.observe_tokens(recipient);
+
+        let service = recipient.service.as_deref().unwrap_or(&args.service);
+        let sms = |service: &str| service.eq_ignore_ascii_case("SMS");
+
+        // Only numbers normalized for a country are reliably classified (and landlines are not
+        // registered with iMessage either, so falling back to SMS cannot help)
+        if args.country.is_some()
+            && (sms(service) || args.fallback_service.as_deref().is_some_and(sms))
+            && is_landline(&recipient.number)
+        {
+            warn!(
+                recipient = recipient.name.as_deref(),
+                number = %DisplayNumber(&recipient.number),
+                "Number on line {} looks like a landline, which cannot receive SMS",
+                recipient.line
+            );
+        }
+
+        // Personalized messages vary in length, so each recipient's is estimated separately
+        if sms(service) {
+            let segments = sms_segments(&render(recipient, template, seed, args));
+
+            if segments > 1 {
+                self.long_messages += 1;
+                self.max_segments = self.max_segments.max(segments);
+            }
+        }
+    }
+
+    fn observe_tokens(&mut self, recipient: &Recipient) {
+        for key in recipient.fields.keys() {
+            if !self.known.contains(key) {
+                self.known.insert(key.clone());
+            }
+        }
+
+        for token in recipient
+            .message
+            .as_deref()
+            .map_or(Vec::new(), find_brace_tokens)
+        {
+            if !self.tokens.iter().any(|t| t == token) {
+                self.tokens.push(token.to_string());
+            }
+        }
+    }
+
+    // Tokens no placeholder provides, which would otherwise be texted literally (e.g., `Hi {name}`)
+    fn unknown_tokens<'a>(
+        &'a self,
+        templates: impl Iterator<Item = &'a String>,
+        args: &Args,
+    ) -> Vec<&'a str> {
+        let tokens = templates
+            .flat_map(|template| find_brace_tokens(template))
+            .chain(self.tokens.iter().map(String::as_str));
+        let mut unknown = Vec::new();
+
+        // The index is only filled in once recipients are selected to send to
+        for token in tokens {
+            if !self.known.contains(token)
+                && token != INDEX_PLACEHOLDER
+                && !args.placeholder.iter().any(|p| p.token == token)
+                && !unknown.contains(&token)
+            {
+                unknown.push(token);
+            }
+        }
+
+        unknown
+    }
+
+    fn warn(&self, templates: &[String], args: &Args) {
+        for token in self.unknown_tokens(templates.iter(), args) {
+            warn!(
+                token,
+                "Message contains {} but no placeholder provides it, so it will be sent as is",
+                token
+            );
+        }
+
+        if self.long_messages > 0 {
+            warn!(
+                "Messages to {} recipient(s) exceed a single SMS segment (up to an estimated {} \
+                 segments each)",
+                self.long_messages, self.max_segments
+            );
+        }
+    }
+}
+
+// The outcome of selecting a single recipient
+enum Selected {
+    Send(Recipient),
+    Skip(SkippedRecipient),
+    // Outside --start and --end or past --limit, so neither sent to nor reported
+    Drop,
+}
+
+// Selects the recipients to send to one at a time, applying --start, --end, the filters, and
+// --limit in that order, so that the recipients need not all be held in memory
+struct Selection<'a> {
+    args: &'a Args,
+    // The templates each recipient without a message of their own is given one of, if several
+    templates: &'a [String],
+    // The path and template of each --template, keyed by lowercase segment
+    segments: &'a HashMap<String, (String, String)>,
+    filters: RecipientFilters,
+    default_attachment: Option<String>,
+    attachment_base: Option<PathBuf>,
+    // Spreads recipients evenly across the templates without --split, the same way each pass
+    seed: u64,
+    quiet: bool,
+    // The number of valid recipients seen, and how many were skipped by each filter
+    loaded: usize,
+    filtered: usize,
+    duplicated: usize,
+    resumed: usize,
+    recent: usize,
+    missing_attachments: usize,
+    // The number of recipients that passed every filter, before --limit is applied
+    eligible: usize,
+}
+
+impl<'a> Selection<'a> {
+    fn new(
+        args: &'a Args,
+        templates: &'a [String],
+        segments: &'a HashMap<String, (String, String)>,
+        number_options: &NumberOptions,
+        default_attachment: Option<String>,
+        seed: u64,
+    ) -> Result<Self> {
+        Ok(Self {
+            args,
+            templates,
+            segments,
+            filters: RecipientFilters {
+                blocklist: args
+                    .blocklist
+                    .as_deref()
+                    .map(|path| Blocklist::load(path, number_options))
+                    .transpose()?,
+                area_codes: args.area_code.clone(),
+                duplicates: (!args.allow_duplicates).then(Duplicates::default),
+                checkpoint: args.resume.as_deref().map(Checkpoint::load).transpose()?,
+                #[cfg(feature = "history")]
+                history: match (&args.history, args.dedupe_window) {
+                    (Some(path), Some(window)) => Some((History::open(path, None)?, window)),
+                    _ => None,
+                },
+            },
+            default_attachment,
+            attachment_base: attachment_base(args),
+            seed,
+            quiet: false,
+            loaded: 0,
+            filtered: 0,
+            duplicated: 0,
+            resumed: 0,
+            recent: 0,
+            missing_attachments: 0,
+            eligible: 0,
+        })
+    }
+
+    // Skips recipients without logging each (e.g., when rereading them to send)
+    fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    fn select(&mut self, loaded: LoadedRecipient) -> amsg_batch::Result<Selected> {
+        let mut recipient = match loaded {
+            LoadedRecipient::Valid(recipient) => recipient,
+            LoadedRecipient::Invalid(s) => return Ok(Selected::Skip(s)),
+        };
+
+        self.loaded += 1;
+
+        let position = self.loaded as u64;
+
+        if self.args.start.is_some_and(|start| position < start)
+            || self.args.end.is_some_and(|end| position > end)
+        {
+            return Ok(Selected::Drop);
+        }
+
+        let skip = |recipient, reason| Ok(Selected::Skip(SkippedRecipient::new(recipient, reason)));
+
+        if let Some(reason) = self.filters.skip_reason(&recipient.number)? {
+            match reason {
+                SkipReason::Blocked if !self.quiet => info!(
+                    recipient = recipient.name.as_deref(),
+                    number = %DisplayNumber(&recipient.number),
+                    status = Status::Skipped.as_str(),
+                    "Skipping blocklisted recipient"
+                ),
+                SkipReason::OutsideAreaCodes => self.filtered += 1,
+                SkipReason::Duplicate => self.duplicated += 1,
+                SkipReason::AlreadySent => self.resumed += 1,
+                SkipReason::RecentlySent => self.recent += 1,
+                _ => {}
+            }
+
+            return skip(recipient, reason);
+        }
+
+        // A missing attachment is caught while checking the recipients rather than mid-send
+        if let Err(e) = resolve_recipient_attachment(
+            &mut recipient,
+            self.default_attachment.as_deref(),
+            self.attachment_base.as_deref(),
+        ) {
+            if !self.quiet {
+                warn!(
+                    recipient = recipient.name.as_deref(),
+                    number = %DisplayNumber(&recipient.number),
+                    status = Status::Skipped.as_str(),
+                    error = %e,
+                    "Skipping recipient whose attachment is missing"
+                );
+            }
+
+            self.missing_attachments += 1;
+            return skip(recipient, SkipReason::MissingAttachment);
+        }
+
+        if let Some((path, template)) = segment_template(&recipient, self.segments)
+            && recipient.message.is_none()
+        {
+            recipient.message = Some(template.clone());
+            recipient.template = Some(path.clone());
+        } else if self.templates.len() > 1 && recipient.message.is_none() {
+            let choice = split_choice(&recipient, self.args).unwrap_or_else(|| {
+                Split::even(self.templates.len()).choose(&recipient.number, self.seed)
+            });
+            recipient.message = Some(self.templates[choice].clone());
+            recipient.template = Some(self.args.message[choice].clone());
+        }
+
+        if let Some(default) = &self.args.placeholder_default {
+            recipient.fill_blank_fields(default);
+        }
+
+        // Blank texts are never sent (e.g., when every placeholder in the message is blank)
+        if may_render_empty(&recipient, &self.templates[0], self.args) {
+            if !self.quiet {
+                warn!(
+                    recipient = recipient.name.as_deref(),
+                    number = %DisplayNumber(&recipient.number),
+                    status = Status::Skipped.as_str(),
+                    "Skipping recipient whose message is empty"
+                );
+            }
+
+            return skip(recipient, SkipReason::EmptyMessage);
+        }
+
+        self.eligible += 1;
+
+        if self.args.limit.is_some_and(|limit| self.eligible > limit) {
+            return Ok(Selected::Drop);
+        }
+
+        // As with the first name, explicitly bound placeholders take precedence
+        recipient
+            .fields
+            .entry(INDEX_PLACEHOLDER.to_string())
+            .or_insert_with(|| self.eligible.to_string());
+
+        Ok(Selected::Send(recipient))
+    }
+
+    // The number of recipients selected to send to so far
+    fn selected(&self) -> usize {
+        self.args
+            .limit
+            .map_or(self.eligible, |limit| limit.min(self.eligible))
+    }
+
+    // Checks --start and --end against the number of valid recipients once all have been seen,
+    // then logs how many recipients each step left out
+    fn summarize(&self) -> Result<()> {
+        if self.args.start.is_some() || self.args.end.is_some() {
+            let start = self.args.start.unwrap_or(1) as usize;
+            let end = self.args.end.map_or(self.loaded, |end| end as usize);
+
+            if let Some(out_of_range) = [start, end].into_iter().find(|&i| i > self.loaded) {
+                bail!(
+                    "Recipient {} is out of range (found only {} valid recipient(s))",
+                    out_of_range,
+                    self.loaded
+                );
+            } else if start > end {
+                bail!("--start ({}) must not exceed --end ({})", start, end);
+            }
+
+            info!(
+                "Sending only to recipients {} through {} of {}",
+                start, end, self.loaded
+            );
+        }
+
+        if self.filtered > 0 {
+            info!(
+                "Filtered out {} recipient(s) outside the selected area code(s)",
+                self.filtered
+            );
+        }
+
+        if self.duplicated > 0 {
+            info!("Dropped {} duplicate recipient(s)", self.duplicated);
+        }
+
+        if self.resumed > 0 {
+            info!(
+                "Resuming run, skipping {} already-sent recipient(s)",
+                self.resumed
+            );
+        }
+
+        if self.missing_attachments > 0 {
+            info!(
+                "Skipping {} recipient(s) whose attachment is missing",
+                self.missing_attachments
+            );
+        }
+
+        if self.recent > 0 {
+            info!(
+                "Skipping {} recipient(s) sent to within the dedupe window",
+                self.recent
+            );
+        }
+
+        if let Some(limit) = self.args.limit {
+            info!(
+                "Limiting run to {} of {} recipient(s)",
+                limit.min(self.eligible),
+                self.eligible
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// A rate is just another way of expressing the delay between messages
+fn send_delay(args: &Args) -> Duration {
+    args.rate
+        .map_or(args.delay, |rate| Duration::from_secs(60) / rate)
+}
+
+// Logs what is about to happen even when --yes skips the prompt, so that a run whose filters or
+// flags did something unexpected can be caught before it gets far
+fn log_send_plan(count: usize, args: &Args) {
+    let service = match (&args.fallback_service, &args.service_column) {
+        (Some(fallback), _) => format!("{} (falling back to {})", args.service, fallback),
+        (None, Some(_)) => format!("{} (unless a recipient's own is given)", args.service),
+        (None, None) => args.service.clone(),
+    };
+
+    if args.dry_run {
+        info!(
+            "[Dry run] Preparing to send to {} recipient(s) via {}",
+            count, service
+        );
+        return;
+    }
+
+    let delay = send_delay(args);
+    // Only the waits between messages are known up front, not how long each send takes
+    let gaps = count.saturating_sub(1) as u32;
+    let mut estimate = delay * gaps;
+
+    // Each batch pause replaces the usual delay when it is longer
+    if let (Some(size), Some(pause)) = (args.batch_size, args.batch_pause) {
+        estimate += pause.saturating_sub(delay) * (gaps / size);
+    }
+
+    info!(
+        "Preparing to send to {} recipient(s) via {} with {} delay (est. {})",
+        count,
+        service,
+        humantime::format_duration(delay),
+        humantime::format_duration(Duration::from_secs(estimate.as_secs()))
+    );
+}
+
+fn confirm_send(
+    first: Option<&Recipient>,
+    count: usize,
+    template: &str,
+    seed: u64,
+    args: &Args,
+) -> Result<bool> {
+    let Some(first) = first else {
+        return Ok(true);
+    };
+
+    if !io::stdin().is_terminal() {
+        bail!(
+            "Cannot ask for confirmation without an interactive terminal (pass --yes to skip it)"
+        );
+    }
+
+    // Events alone go to stdout, so the prompt is shown on stderr when they are written
+    let mut out: Box<dyn Write> = if args.events {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+
+    writeln!(out, "Recipients: {}", count)?;
+    if args.service_column.is_some() {
+        writeln!(
+            out,
+            "Service: {} (unless a recipient's own is given)",
+            args.service
+        )?;
+    } else {
+        writeln!(out, "Service: {}", args.service)?;
+    }
+    writeln!(
+        out,
+        "First message:\n\n{}\n",
+        render(first, template, seed, args).trim_end()
+    )?;
+    write!(out, "Send to {} recipients? [y/N] ", count)?;
+    out.flush().context("Failed to flush prompt")?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read confirmation")?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// The size and modification time of the recipients file, by which a change to it between the
+// two passes over it is noticed
+fn file_stamp(path: &str) -> Result<(u64, Option<SystemTime>)> {
+    let metadata = fs::metadata(path).context(format!("Failed to read metadata of {}", path))?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+fn wait_until(at: DateTime<Local>, dry_run: bool) -> Result<()> {
+    let wait = (at - Local::now()).to_std().unwrap_or_default();
+
+    if dry_run {
+        info!(at = %at.to_rfc3339(), "[Dry run] Would wait until the scheduled send time");
+        return Ok(());
+    }
+
+    info!(
+        at = %at.to_rfc3339(),
+        wait = %humantime::format_duration(Duration::from_secs(wait.as_secs())),
+        "Waiting until the scheduled send time"
+    );
+    thread::sleep(wait);
+
+    Ok(())
+}
+
+// Quits Messages once a run is over if asked to, though failing to is not worth failing over
+fn quit_messages_after(args: &Args) {
+    if !args.quit_after || args.dry_run {
+        return;
+    }
+
+    match quit_messages(args.send_timeout) {
+        Ok(()) => info!("Quit Messages"),
+        Err(e) => warn!(error = %e, "Failed to quit Messages"),
+    }
+}
+
+fn send_to_group(group: &str, message: &str, args: &Args) -> Result<ExitCode> {
+    let attachment = args
+        .attachment
+        .as_deref()
+        .map(resolve_attachment)
+        .transpose()?;
+
+    if args.dry_run {
+        info!(
+            group = %group,
+            attachment = attachment.as_deref(),
+            text = %message,
+            "[Dry run] Would send message to group"
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let result = retry(group, args.max_retries, args.retry_base_delay, || {
+        send_group_message(message, group, attachment.as_deref(), args.send_timeout)
+    });
+
+    if let Err(e) = result {
+        error!(
+            group = %group,
+            status = Status::Failed.as_str(),
+            error = %e,
+            "Failed to send message to group"
+        );
+        return Ok(ExitCode::from(EXIT_SEND_FAILED));
+    }
+
+    info!(group = %group, status = Status::Sent.as_str(), "Message sent to group");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Sends the first recipient's message to the tester's own number to catch broken templates
+fn send_test_message(
+    sender: &dyn MessageSender,
+    first: &Recipient,
+    template: &str,
+    seed: u64,
+    test_number: &str,
+    args: &Args,
+) -> Result<()> {
+    let mut fields = first.fields.clone();
+
+    for placeholder in args.placeholder.iter().filter(|p| p.column == Column::Name) {
+        fields.insert(placeholder.token.clone(), "Test".to_string());
+    }
+
+    // Spun as for the first recipient, so that the test differs from their message only in name
+    let message = render_message_with(
+        first.message.as_deref().unwrap_or(template),
+        &fields,
+        |template| spin_message_for(template, &first.number, seed),
+        args,
+    );
+    let service = first.service.as_deref().unwrap_or(&args.service);
+
+    if args.dry_run {
+        info!(
+            number = %DisplayNumber(test_number),
+            service,
+            attachment = first.attachment.as_deref(),
+            text = %message,
+            "[Dry run] Would send test message"
+        );
+        return Ok(());
+    }
+
+    let service = send_with_retries(
+        test_number,
+        service,
+        args.fallback_service.as_deref(),
+        args.max_retries,
+        args.retry_base_delay,
+        |service| sender.send(&message, test_number, service, first.attachment.as_deref()),
+    )
+    .context("Failed to send test message")?;
+
+    info!(
+        number = %DisplayNumber(test_number),
+        service = %service,
+        "Test message sent, check it before continuing"
+    );
+
+    Ok(())
+}
+
+fn check_recipients(rows: usize, problems: &ValidationErrors) -> Result<()> {
+    let invalid = problems.lines();
+
+    println!(
+        "Checked {} row(s): {} valid, {} invalid",
+        rows,
+        rows.saturating_sub(invalid),
+        invalid
+    );
+
+    for problem in problems.problems() {
+        match problem.line {
+            Some(line) => println!("  Line {}: {}", line, problem.message),
+            None => println!("  {}", problem.message),
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Found {} problem(s) in {} invalid row(s)",
+            problems.len(),
+            invalid
+        );
+    }
+
+    Ok(())
+}
+
+fn init_logging(args: &Args) {
+    let progress = (!args.no_progress).then(IndicatifLayer::new);
+
+    // Route logs through the progress bar (if any) so that lines are not garbled, keeping them
+    // out of the way of any events on stdout
+    let writer = match (&progress, args.events) {
+        (Some(progress), false) => BoxMakeWriter::new(progress.get_stdout_writer()),
+        (Some(progress), true) => BoxMakeWriter::new(progress.get_stderr_writer()),
+        (None, false) => BoxMakeWriter::new(io::stdout),
+        (None, true) => BoxMakeWriter::new(io::stderr),
+    };
+
+    // Colors are only for people watching the logs, who nonetheless may not want them
+    let terminal = if args.events {
+        io::stderr().is_terminal()
+    } else {
+        io::stdout().is_terminal()
+    };
+    let ansi = terminal
+        && !args.no_color
+        && env::var_os(NO_COLOR_ENV).is_none_or(|value| value.is_empty());
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    let fmt_layer = match args.log_format {
+        LogFormat::Pretty => fmt_layer
+            .event_format(StatusColors(Format::default()))
+            .boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
+
+    // Flags take precedence over RUST_LOG, which only sets the level here (e.g., `debug`) rather
+    // than filtering by target
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => env::var(LOG_LEVEL_ENV)
+            .ok()
+            .and_then(|level| level.trim().parse().ok())
+            .unwrap_or(LevelFilter::INFO),
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+
+    // The scripts are only logged at debug level, so as not to clutter normal runs
+    let level = if args.print_script {
+        level.max(LevelFilter::DEBUG)
+    } else {
+        level
+    };
+
+    tracing_subscriber::registry()
+        .with(level)
+        .with(fmt_layer)
+        .with(progress)
+        .init();
+}
+
+// Colors the whole line logged for each recipient's outcome (green if sent, yellow if skipped,
+// and red if failed), so that failures stand out in a long run
+struct StatusColors<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for StatusColors<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut status = StatusField(None);
+        event.record(&mut status);
+
+        let color = match status.0.as_deref() {
+            Some("sent") => "32",
+            Some("skipped") => "33",
+            Some("failed") => "31",
+            _ => return self.0.format_event(ctx, writer, event),
+        };
+
+        if !writer.has_ansi_escapes() {
+            return self.0.format_event(ctx, writer, event);
+        }
+
+        // The line is formatted without colors of its own, which would end this color early
+        let mut line = String::new();
+        self.0.format_event(ctx, Writer::new(&mut line), event)?;
+        writeln!(
+            writer,
+            "\x1b[{}m{}\x1b[0m",
+            color,
+            line.trim_end_matches('\n')
+        )
+    }
+}
+
+// The `status` field logged with each recipient's outcome
+struct StatusField(Option<String>);
+
+impl Visit for StatusField {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "status" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+}
+
+// Set on the first Ctrl-C, after which no new sends are started
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(EXIT_INTERRUPTED.into());
+        }
+
+        warn!("Interrupted, finishing in-flight sends (press Ctrl-C again to exit immediately)");
+    })
+    .context("Failed to install Ctrl-C handler")
+}
+
+// Sends to `count` recipients, showing their progress and stopping early on Ctrl-C
+fn send_messages<I: Iterator<Item = amsg_batch::Result<Recipient>> + Send>(
+    sender: &dyn MessageSender,
+    recipients: I,
+    count: usize,
+    template: &str,
+    seed: u64,
+    args: &Args,
+    records: &mut Records,
+) -> Result<Tally> {
+    let progress = info_span!("progress");
+    progress.pb_set_style(
+        &ProgressStyle::with_template(PROGRESS_TEMPLATE)
+            .context("Invalid progress bar template")?,
+    );
+    progress.pb_set_length(count as u64);
+    let _progress = progress.enter();
+
+    let mut limiter =
+        RateLimiter::new(send_delay(args)).with_jitter(f64::from(args.jitter) / 100.0);
+
+    if let (Some(size), Some(pause)) = (args.batch_size, args.batch_pause) {
+        limiter = limiter.with_batches(size as usize, pause);
+    }
+
+    if args.adaptive {
+        limiter = limiter.with_adaptive(args.max_delay);
+    }
+
+    if !args.dry_run {
+        handle_interrupts()?;
+    }
+
+    let options = BatchOptions {
+        service: &args.service,
+        fallback_service: args.fallback_service.as_deref(),
+        max_retries: args.max_retries,
+        retry_base_delay: args.retry_base_delay,
+        concurrency: args.concurrency,
+        limiter,
+        dry_run: args.dry_run,
+        render: &|recipient| render(recipient, template, seed, args),
+        interrupted: &INTERRUPTED,
+        progress: &|| progress.pb_inc(1),
+    };
+
+    Ok(send_batch(sender, recipients, count, &options, records)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses `cli` (minus the program name) along with `config` as the config file
+    fn parse_with_config(name: &str, config: &str, cli: &[&str]) -> Result<Args> {
+        let path = env::temp_dir().join(format!("amsg-batch-{}-config-test.toml", name));
+        fs::write(&path, config).unwrap();
+
+        let cli = iter::once(env!("CARGO_PKG_NAME"))
+            .chain(cli.iter().copied())
+            .chain(["--config", path.to_str().unwrap()])
+            .map(OsString::from)
+            .collect();
+        let merged = merge_config(cli);
+        fs::remove_file(&path).unwrap();
+
+        Ok(Cli::try_parse_from(merged?)?.into_args())
+    }
+
+    // Row 3 repeats the number of row 1
+    const RECIPIENTS: &str = "A,+14155550101\nB,+14155550102\nA2,+14155550101\nC,+14155550103\n\
+                              D,+14155550104\nE,+14155550105\n";
+
+    fn recipients() -> Vec<Recipient> {
+        stream_recipients(
+            RECIPIENTS.as_bytes(),
+            &CsvOptions::default(),
+            &[parse_placeholder("{name}").unwrap()],
+            &NumberOptions::default(),
+        )
+        .and_then(|stream| stream.load())
+        .unwrap()
+        .recipients
+    }
+
+    fn args(cli: &[&str]) -> Args {
+        let cli = [
+            env!("CARGO_PKG_NAME"),
+            "-r",
+            "r.csv",
+            "-p",
+            "{name}",
+            "--text",
+            "Hi",
+        ]
+        .into_iter()
+        .chain(cli.iter().copied());
+        Cli::try_parse_from(cli).unwrap().into_args()
+    }
+
+    // The name and `{index}` of each recipient selected to send to, in order
+    fn select(recipients: Vec<Recipient>, args: &Args) -> Result<Vec<(String, String)>> {
+        let templates = message_templates(args)?;
+        let segments = HashMap::new();
+        let mut selection = Selection::new(
+            args,
+            &templates,
+            &segments,
+            &NumberOptions::default(),
+            None,
+            0,
+        )?;
+        let mut selected = Vec::new();
+
+        for recipient in recipients {
+            if let Selected::Send(recipient) =
+                selection.select(LoadedRecipient::Valid(recipient))?
+            {
+                selected.push((
+                    recipient.name.unwrap_or_default(),
+                    recipient.fields[INDEX_PLACEHOLDER].clone(),
+                ));
+            }
+        }
+
+        selection.summarize()?;
+        assert_eq!(selection.selected(), selected.len());
+        Ok(selected)
+    }
+
+    fn named(names: &[&str]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), (i + 1).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn limit_counts_only_recipients_that_pass_the_filters() {
+        // The duplicate is skipped without using up the limit or an index
+        assert_eq!(
+            select(recipients(), &args(&["--limit", "3"])).unwrap(),
+            named(&["A", "B", "C"])
+        );
+        assert_eq!(
+            select(recipients(), &args(&["--limit", "3", "--allow-duplicates"])).unwrap(),
+            named(&["A", "B", "A2"])
+        );
+        assert_eq!(
+            select(recipients(), &args(&["--limit", "10"])).unwrap(),
+            named(&["A", "B", "C", "D", "E"])
+        );
+    }
+
+    #[test]
+    fn index_numbers_recipients_after_skips() {
+        let blocklist = env::temp_dir().join("amsg-batch-index-blocklist-test.txt");
+        fs::write(&blocklist, "+14155550102\n").unwrap();
+
+        let selected = select(
+            recipients(),
+            &args(&["--blocklist", blocklist.to_str().unwrap()]),
+        );
+        fs::remove_file(&blocklist).unwrap();
+
+        assert_eq!(selected.unwrap(), named(&["A", "C", "D", "E"]));
+    }
+
+    #[test]
+    fn start_and_end_select_by_position_before_the_filters_and_limit() {
+        assert_eq!(
+            select(recipients(), &args(&["--start", "4", "--end", "5"])).unwrap(),
+            named(&["C", "D"])
+        );
+        assert_eq!(
+            select(recipients(), &args(&["--start", "5"])).unwrap(),
+            named(&["D", "E"])
+        );
+
+        // Recipients before --start are never seen, so none of them is duplicated
+        assert_eq!(
+            select(recipients(), &args(&["--start", "2", "--end", "4"])).unwrap(),
+            named(&["B", "A2", "C"])
+        );
+        assert_eq!(
+            select(recipients(), &args(&["--end", "4", "--limit", "2"])).unwrap(),
+            named(&["A", "B"])
+        );
+        assert_eq!(
+            select(recipients(), &args(&["--end", "4", "--limit", "3"])).unwrap(),
+            named(&["A", "B", "C"])
+        );
+    }
+
+    #[test]
+    fn start_and_end_must_fall_within_the_recipients() {
+        let e = select(recipients(), &args(&["--start", "7"]))
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Recipient 7 is out of range (found only 6 valid recipient(s))"
+        );
+
+        let e = select(recipients(), &args(&["--start", "4", "--end", "2"]))
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "--start (4) must not exceed --end (2)");
+    }
+
+    #[test]
+    fn start_and_end_select_from_the_shuffled_order() {
+        let args = args(&[
+            "--shuffle",
+            "--seed",
+            "7",
+            "--start",
+            "2",
+            "--end",
+            "4",
+            "--allow-duplicates",
+        ]);
+        let mut shuffled = recipients();
+        shuffle_recipients(&mut shuffled, args.seed);
+
+        let window = shuffled[1..4]
+            .iter()
+            .map(|recipient| recipient.name.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(window, ["B", "A2", "C"]);
+        assert_eq!(select(shuffled.clone(), &args).unwrap(), named(&window));
+    }
+
+    #[test]
+    fn config_fills_in_options_not_on_the_command_line() {
+        let config =
+            "service = \"SMS\"\ndelay = \"5s\"\nplaceholder = [\"{name}\", \"{city}=2\"]\n";
+        let args = parse_with_config("fill", config, &["-r", "r.csv", "--text", "Hi"]).unwrap();
+        assert_eq!(args.service, "SMS");
+        assert_eq!(args.delay, Duration::from_secs(5));
+        assert_eq!(args.placeholder.len(), 2);
+
+        let args = parse_with_config(
+            "override",
+            config,
+            &["-r", "r.csv", "--text", "Hi", "--service", "iMessage"],
+        )
+        .unwrap();
+        assert_eq!(args.service, "iMessage");
+        assert_eq!(args.delay, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn config_yields_to_conflicting_command_line_options() {
+        // --rate is an alternative to --delay, so it overrides the file's delay
+        let args = parse_with_config(
+            "rate",
+            "delay = \"5s\"\n",
+            &["-r", "r.csv", "--text", "Hi", "--rate", "30"],
+        )
+        .unwrap();
+        assert_eq!(args.rate, Some(30));
+        assert_eq!(args.delay, parse_duration(DEFAULT_DELAY).unwrap());
+
+        // As is a message given either way
+        let config = "message = [\"a.txt\", \"b.txt\"]\n";
+        let args = parse_with_config("text", config, &["-r", "r.csv", "--text", "Hi"]).unwrap();
+        assert!(args.message.is_empty());
+        assert_eq!(args.text.as_deref(), Some("Hi"));
+
+        let args = parse_with_config("messages", config, &["-r", "r.csv"]).unwrap();
+        assert_eq!(args.message, ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn config_counts_flags() {
+        let args = parse_with_config("verbose", "verbose = 2\n", &["-r", "r.csv", "--text", "Hi"])
+            .unwrap();
+        assert_eq!(args.verbose, 2);
+
+        let args = parse_with_config(
+            "verbose-cli",
+            "verbose = 2\n",
+            &["-r", "r.csv", "--text", "Hi", "-v"],
+        )
+        .unwrap();
+        assert_eq!(args.verbose, 1);
+
+        let args = parse_with_config(
+            "flag",
+            "yes = true\nshuffle = false\n",
+            &["preview", "-r", "r.csv", "--text", "Hi"],
+        )
+        .unwrap();
+        assert!(args.yes && args.dry_run && !args.shuffle);
+    }
+
+    #[test]
+    fn config_rejects_unknown_options() {
+        let e = parse_with_config(
+            "unknown",
+            "colour = \"red\"\n",
+            &["-r", "r.csv", "--text", "Hi"],
+        )
+        .err()
+        .unwrap();
+        assert!(e.to_string().starts_with("Unknown option colour in config"));
+
+        let e = parse_with_config("table", "[service]\nname = \"SMS\"\n", &["--text", "Hi"])
+            .err()
+            .unwrap();
+        assert!(
+            e.to_string()
+                .starts_with("Unsupported value for service in config")
+        );
+    }
 }